@@ -0,0 +1,54 @@
+use std::time::Instant;
+
+use nested_template::NestedTemplate;
+
+const ITERATIONS: u32 = 100_000;
+
+fn page() -> NestedTemplate {
+    let mut header = NestedTemplate::new("<header><h1>{title}</h1><nav>{nav}</nav></header>");
+    header.add_sub_template("title", NestedTemplate::new("Nested templates"));
+    header.add_sub_template(
+        "nav",
+        NestedTemplate::new("<a href=\"/\">Home</a><a href=\"/about\">About</a>"),
+    );
+
+    let mut body = NestedTemplate::new(
+        "<main><p>{content}</p><style>p {{ color: red; }}</style></main><footer>{footer}</footer>",
+    );
+    body.add_sub_template("content", NestedTemplate::new("Some page content"));
+    body.add_sub_template("footer", NestedTemplate::new("&copy; nobody"));
+
+    let mut page = NestedTemplate::new("<!DOCTYPE html><html>{header}<body>{body}</body></html>");
+    page.add_sub_template("header", header);
+    page.add_sub_template("body", body);
+    page
+}
+
+fn main() {
+    let page = page();
+
+    let start = Instant::now();
+    let mut expected = String::new();
+    for _ in 0..ITERATIONS {
+        expected = page.render().unwrap();
+    }
+    let render_time = start.elapsed();
+
+    let start = Instant::now();
+    let compiled = page.compile().unwrap();
+    let mut actual = String::new();
+    for _ in 0..ITERATIONS {
+        actual = compiled.render().unwrap();
+    }
+    let compiled_time = start.elapsed();
+
+    assert_eq!(expected, actual, "compiled output differs from render()");
+    println!(
+        "render():           {:?} for {} renders",
+        render_time, ITERATIONS
+    );
+    println!(
+        "compile().render(): {:?} for {} renders",
+        compiled_time, ITERATIONS
+    );
+}
@@ -0,0 +1,872 @@
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::Arc;
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::filter::FilterScope;
+use crate::html::{self, HtmlContext, Scan};
+use crate::parser::offset_in;
+use crate::{
+    Context, Escape, Filter, Location, Missing, NestedTemplate, Node, ParseError, Registry, Value,
+};
+
+/// A [`NestedTemplate`] tree that has been parsed once up front so it can be rendered many times
+/// without scanning the bodies again. Created with [`NestedTemplate::compile`].
+#[derive(Debug)]
+pub struct CompiledTemplate<'a> {
+    template: &'a NestedTemplate,
+    nodes: Vec<Node<'a>>,
+    sub_templates: HashMap<&'a str, CompiledTemplate<'a>>,
+    /// The HTML context of each placeholder, worked out the first time the template is rendered
+    /// as HTML. Shared with the [`Parsed`] form of the template, if it has one
+    html_scan: Arc<OnceLock<Scan>>,
+}
+
+impl<'a> CompiledTemplate<'a> {
+    pub(crate) fn new(
+        template: &'a NestedTemplate,
+        nodes: Vec<Node<'a>>,
+        sub_templates: HashMap<&'a str, CompiledTemplate<'a>>,
+    ) -> CompiledTemplate<'a> {
+        CompiledTemplate {
+            template,
+            nodes,
+            sub_templates,
+            html_scan: Arc::default(),
+        }
+    }
+
+    /// A copy of the parse that does not borrow the templates, so it can be kept next to them.
+    pub(crate) fn to_parsed(&self) -> Parsed {
+        let body = &self.template.body;
+        let mut text = Vec::new();
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.map_text(&mut |part| {
+                    text.push(Text::new(body, part));
+                    ""
+                })
+            })
+            .collect();
+        let sub_templates = self
+            .sub_templates
+            .iter()
+            .map(|(name, compiled)| (name.to_string(), compiled.to_parsed()))
+            .collect();
+        Parsed {
+            nodes,
+            text,
+            sub_templates,
+            html_scan: Arc::clone(&self.html_scan),
+        }
+    }
+
+    pub(crate) fn template(&self) -> &'a NestedTemplate {
+        self.template
+    }
+
+    pub fn nodes(&self) -> &[Node<'a>] {
+        &self.nodes
+    }
+
+    pub fn render(&self) -> Result<String, ParseError> {
+        self.render_with(&Context::new())
+    }
+
+    /// Renders the template, looking placeholders up in `ctx` before falling back to the values
+    /// and sub-templates registered on the template.
+    pub fn render_with(&self, ctx: &Context) -> Result<String, ParseError> {
+        let mut rendered_template = String::new();
+        self.render_into(&mut rendered_template, State::new(ctx))?;
+        Ok(rendered_template)
+    }
+
+    /// Renders into `out`. `parent` is the state of the template this one was inserted into.
+    pub(crate) fn render_into(&self, out: &mut String, parent: State) -> Result<(), ParseError> {
+        // Only `{% extends %}` hands blocks down to the template it renders
+        let include = Include {
+            template: self.template,
+            extends: parent.overrides.is_some(),
+            parent: parent.includes,
+        };
+        include.check(parent.ctx.max_depth())?;
+        let filters = FilterScope {
+            filters: &self.template.filters,
+            parent: parent.filters,
+        };
+        let state = State {
+            filters: Some(&filters),
+            includes: Some(&include),
+            output_start: out.len(),
+            html: None,
+            ..parent.enter(self.template)
+        };
+        let extends = self.nodes.iter().find_map(|node| match node {
+            Node::Extends(base) => Some(*base),
+            _ => None,
+        });
+
+        match extends {
+            Some(base) => self.render_extends(base, out, state),
+            None => self.render_nodes(&self.nodes, out, state),
+        }
+    }
+
+    // Renders `base` in place of this template, with the blocks of this template overriding its own
+    fn render_extends(&self, base: &str, out: &mut String, state: State) -> Result<(), ParseError> {
+        let overrides = Overrides {
+            level: Level {
+                template: self,
+                nodes: &self.nodes,
+                html: None,
+            },
+            parent: state.overrides,
+        };
+        let state = State {
+            overrides: Some(&overrides),
+            ..state
+        };
+
+        match self.resolve(base, state)? {
+            Binding::Template(template) => template.render_into(out, state),
+            Binding::Compiled(compiled) => compiled.render_into(out, state),
+            Binding::Parsed(template, parsed) => parsed.compile(template).render_into(out, state),
+            Binding::Value(value) => Err(ParseError::NotTemplate(base.to_string(), value.kind())),
+        }
+    }
+
+    fn render_block(
+        &self,
+        name: &str,
+        body: &[Node],
+        out: &mut String,
+        state: State,
+    ) -> Result<(), ParseError> {
+        // Every version of the block, from the most derived template down to this one. Overrides
+        // are linked from the most recently entered template, which is the least derived
+        let mut found = Vec::new();
+        let mut overrides = state.overrides;
+        while let Some(level) = overrides {
+            if let Some(nodes) = find_block(level.level.nodes, name) {
+                found.push((level.level.template, nodes));
+            }
+            overrides = level.parent;
+        }
+        found.reverse();
+
+        // The versions from other templates are escaped for where the block is in this one
+        let scans: Vec<Option<Scan>> = match state.escape {
+            Escape::Html => {
+                let here = state.html.unwrap_or_else(|| self.html_scan());
+                let offset = offset_in(&self.template.body, name);
+                found
+                    .iter()
+                    .map(|(template, nodes)| here.block_at(offset, &template.template.body, nodes))
+                    .collect()
+            }
+            _ => found.iter().map(|_| None).collect(),
+        };
+        let mut chain: Vec<Level> = found
+            .iter()
+            .zip(&scans)
+            .map(|(&(template, nodes), scan)| Level {
+                template,
+                nodes,
+                html: scan.as_ref(),
+            })
+            .collect();
+        chain.push(Level {
+            template: self,
+            nodes: body,
+            html: state.html,
+        });
+
+        render_levels(&chain, out, state)
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        out: &mut String,
+        state: State,
+    ) -> Result<(), ParseError> {
+        for node in nodes {
+            match node {
+                Node::Literal(text) => out.push_str(text),
+                Node::Placeholder { name, filters } => {
+                    let context = match state.escape {
+                        Escape::Html => Some(self.html_context_in(name, state.html)),
+                        _ => None,
+                    };
+                    let start = out.len();
+                    self.render_placeholder(name, filters, out, state, context)?;
+                    if state.indent {
+                        indent_lines(out, start, state.output_start);
+                    }
+                }
+                Node::If {
+                    condition,
+                    negated,
+                    then,
+                    otherwise,
+                } => {
+                    if self.is_truthy(condition, state) != *negated {
+                        self.render_nodes(then, out, state)?;
+                    } else {
+                        self.render_nodes(otherwise, out, state)?;
+                    }
+                }
+                Node::Each {
+                    list,
+                    item,
+                    body,
+                    empty,
+                } => self.render_each(list, item, body, empty, out, state)?,
+                Node::Block { name, body } => self.render_block(name, body, out, state)?,
+                Node::Super => render_levels(state.supers, out, state)?,
+                // Only does anything at the top of a template, where render_into handles it
+                Node::Extends(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn render_each(
+        &self,
+        list: &str,
+        item: &str,
+        body: &[Node],
+        empty: &[Node],
+        out: &mut String,
+        state: State,
+    ) -> Result<(), ParseError> {
+        let items = match self.resolve(list, state)? {
+            Binding::Value(Value::List(items)) => items,
+            Binding::Value(value) => {
+                return Err(ParseError::NotList(list.to_string(), value.kind()))
+            }
+            Binding::Template(_) | Binding::Compiled(_) | Binding::Parsed(..) => {
+                return Err(ParseError::NotList(list.to_string(), "template"))
+            }
+        };
+
+        if items.is_empty() {
+            return self.render_nodes(empty, out, state);
+        }
+
+        for (index, value) in items.iter().enumerate() {
+            let meta: Value = [
+                ("index", Value::from(index as i64)),
+                ("number", Value::from(index as i64 + 1)),
+                ("first", Value::from(index == 0)),
+                ("last", Value::from(index + 1 == items.len())),
+                ("length", Value::from(items.len() as i64)),
+            ]
+            .into_iter()
+            .collect();
+
+            let item_local = Local {
+                name: item,
+                binding: Binding::Value(value),
+                parent: state.locals,
+            };
+            let loop_local = Local {
+                name: "loop",
+                binding: Binding::Value(&meta),
+                parent: Some(&item_local),
+            };
+            let state = State {
+                locals: Some(&loop_local),
+                ..state
+            };
+            self.render_nodes(body, out, state)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn html_scan(&self) -> &Scan {
+        self.html_scan
+            .get_or_init(|| html::contexts(&self.template.body, &self.nodes))
+    }
+
+    /// The HTML context of the placeholder `name`, found in `scan` if the nodes it is in are not
+    /// placed where they are written in this template.
+    pub(crate) fn html_context_in(&self, name: &str, scan: Option<&Scan>) -> HtmlContext {
+        let offset = offset_in(&self.template.body, name);
+        scan.unwrap_or_else(|| self.html_scan()).context(offset)
+    }
+
+    /// Whether a `{#if}` condition holds. Missing names, `false`, zero, empty strings and empty
+    /// lists or maps are false, and everything else, including any template, is true.
+    pub(crate) fn is_truthy(&self, name: &str, state: State) -> bool {
+        match self.resolve(name, state) {
+            Ok(Binding::Value(value)) => value.is_truthy(),
+            Ok(Binding::Template(_) | Binding::Compiled(_) | Binding::Parsed(..)) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn render_placeholder(
+        &self,
+        name: &str,
+        filters: &[Filter],
+        out: &mut String,
+        state: State,
+        context: Option<HtmlContext>,
+    ) -> Result<(), ParseError> {
+        // Only a missing `name` falls back. Anything missing inside what it is bound to is left
+        // to the placeholders in there
+        let binding = match self.resolve(name, state) {
+            Err(ParseError::MissingTemplate(missing)) => {
+                let default = filters.iter().position(|filter| filter.name == "default");
+                if let Some(i) = default {
+                    // The default is written in the template, so it is as trusted as the rest of
+                    // it. Only the filters after it apply
+                    let text = filters[i].args[0].as_str().unwrap_or_default();
+                    let rest = &filters[i + 1..];
+                    return self.filter_into(name, text.into(), true, rest, out, state, context);
+                }
+                return match state.ctx.missing() {
+                    Missing::Error => Err(ParseError::MissingTemplate(missing)),
+                    Missing::Empty => Ok(()),
+                    Missing::Keep => {
+                        out.push_str(self.tag(name));
+                        Ok(())
+                    }
+                    Missing::Callback(callback) => match callback(name) {
+                        Some(text) => {
+                            self.filter_into(name, text.into(), false, filters, out, state, context)
+                        }
+                        None => Err(ParseError::MissingTemplate(missing)),
+                    },
+                };
+            }
+            binding => binding?,
+        };
+
+        let built;
+        let (template, compiled) = match binding {
+            Binding::Value(Value::Safe(text)) => {
+                return self.filter_into(name, text.into(), true, filters, out, state, context)
+            }
+            Binding::Value(Value::String(text)) => {
+                return self.filter_into(name, text.into(), false, filters, out, state, context)
+            }
+            // Structured values can only be inserted as JSON
+            Binding::Value(value) if filters.first().is_some_and(|f| f.name == "json") => {
+                let text = value.to_json().into();
+                return self.filter_into(name, text, false, &filters[1..], out, state, context);
+            }
+            Binding::Value(value) => match value.as_text() {
+                Some(text) => {
+                    return self.filter_into(name, text.into(), false, filters, out, state, context)
+                }
+                None => return Err(ParseError::NotText(name.to_string(), value.kind())),
+            },
+            Binding::Template(template) => (template, None),
+            Binding::Compiled(compiled) => (compiled.template, Some(compiled)),
+            Binding::Parsed(template, parsed) => {
+                built = parsed.compile(template);
+                (template, Some(&built))
+            }
+        };
+
+        // Blocks only carry through `{% extends %}`, not into inserted templates
+        let state = State {
+            overrides: None,
+            supers: &[],
+            ..state
+        };
+
+        // A template that escapes its values the way this one does writes markup that is already
+        // escaped. Where the markup cannot go as it is, it is rendered without escaping and
+        // escaped once as a whole instead
+        let inherits = template
+            .escape_mode()
+            .is_none_or(|escape| escape == state.escape);
+        let markup = inherits
+            && matches!(
+                context,
+                None | Some(HtmlContext::Text | HtmlContext::Attribute)
+            );
+        let trusted = template.safe || markup;
+        let inner = match template.escape_mode() {
+            None if !trusted => State {
+                escape: Escape::None,
+                ..state
+            },
+            _ => state,
+        };
+
+        // Trusted templates go straight into the output. Anything else is escaped as a whole
+        let unfiltered = filters.iter().all(|filter| filter.name == "default");
+        if unfiltered && (trusted || state.escape == Escape::None) {
+            return match compiled {
+                Some(compiled) => compiled.render_into(out, state),
+                None => template.render_into(out, state),
+            };
+        }
+
+        let mut buffer = String::new();
+        match compiled {
+            Some(compiled) => compiled.render_into(&mut buffer, inner)?,
+            None => template.render_into(&mut buffer, inner)?,
+        }
+        self.filter_into(name, buffer.into(), trusted, filters, out, state, context)
+    }
+
+    // Runs `text` through `filters` and writes the result to `out`, escaped unless it is trusted
+    #[allow(clippy::too_many_arguments)]
+    fn filter_into(
+        &self,
+        name: &str,
+        mut text: Cow<str>,
+        mut trusted: bool,
+        filters: &[Filter],
+        out: &mut String,
+        state: State,
+        context: Option<HtmlContext>,
+    ) -> Result<(), ParseError> {
+        for filter in filters {
+            match filter.name {
+                "default" => (),
+                "safe" => trusted = true,
+                "escape" => {
+                    if !trusted {
+                        let escape = match state.escape {
+                            Escape::None => Escape::Html,
+                            escape => escape,
+                        };
+                        let mut escaped = String::with_capacity(text.len());
+                        let state = State { escape, ..state };
+                        self.insert(name, &text, &mut escaped, state, context)?;
+                        text = escaped.into();
+                    }
+                    trusted = true;
+                }
+                _ => {
+                    let apply = FilterScope::get(state.filters, filter.name)
+                        .ok_or_else(|| ParseError::UnknownFilter(filter.name.to_string()))?;
+                    let filtered = apply(&text, &filter.args).map_err(|message| {
+                        ParseError::FilterFailed(filter.name.to_string(), message)
+                    })?;
+                    text = filtered.into();
+                }
+            }
+        }
+
+        if trusted {
+            out.push_str(&text);
+            Ok(())
+        } else {
+            self.insert(name, &text, out, state, context)
+        }
+    }
+
+    // The whole tag that `name` was written in, delimiters and filters included
+    fn tag(&self, name: &str) -> &str {
+        let (body, syntax) = (&self.template.body, &self.template.syntax);
+        let start = offset_in(body, name);
+        let open = body[..start].rfind(syntax.open()).unwrap_or(start);
+        let close = body[start..]
+            .find(syntax.close())
+            .map_or(body.len(), |end| start + end + syntax.close().len());
+        &body[open..close]
+    }
+
+    // Writes untrusted text to `out`, escaped for where the placeholder `name` sits
+    fn insert(
+        &self,
+        name: &str,
+        text: &str,
+        out: &mut String,
+        state: State,
+        context: Option<HtmlContext>,
+    ) -> Result<(), ParseError> {
+        match context {
+            Some(context) => context.escape_into(text, out).map_err(|reason| {
+                let body = &self.template.body;
+                let loc = Location::new(body, offset_in(body, name));
+                ParseError::Unsafe(loc, reason).in_template(self.template.name())
+            }),
+            None => {
+                state.escape.escape_into(text, out);
+                Ok(())
+            }
+        }
+    }
+
+    /// Looks up a placeholder name, following dotted paths like `layout.header.title` through
+    /// sub-templates, maps and lists. A name that is registered as a whole wins over a path.
+    pub(crate) fn resolve<'r>(
+        &'r self,
+        name: &str,
+        state: State<'r>,
+    ) -> Result<Binding<'r>, ParseError> {
+        if let Some(binding) = self.lookup(name, state) {
+            return Ok(binding);
+        }
+
+        let mut segments = name.split('.');
+        let first = segments.next().unwrap_or_default();
+        let mut binding = self.lookup(first, state);
+        let mut end = first.len();
+
+        for segment in segments {
+            if binding.is_none() {
+                break;
+            }
+            binding = binding.and_then(|binding| binding.get(segment));
+            end += 1 + segment.len();
+        }
+
+        // `end` stops after the first segment that could not be found
+        binding.ok_or_else(|| ParseError::MissingTemplate(name[..end].to_string()))
+    }
+
+    fn lookup<'r>(&'r self, name: &str, state: State<'r>) -> Option<Binding<'r>> {
+        let mut local = state.locals;
+        while let Some(Local {
+            name: local_name,
+            binding,
+            parent,
+        }) = local
+        {
+            if *local_name == name {
+                return Some(*binding);
+            }
+            local = *parent;
+        }
+
+        let ctx = state.ctx;
+        if let Some(value) = ctx.value(name) {
+            Some(Binding::Value(value))
+        } else if let Some(template) = ctx.template(name) {
+            Some(Binding::Template(template))
+        } else if let Some(binding) = Binding::Compiled(self).get(name) {
+            Some(binding)
+        } else {
+            state.registry?.binding(name)
+        }
+    }
+}
+
+/// A [`CompiledTemplate`] that does not borrow the template tree it was compiled from, so the two
+/// can be kept together. Turning it back into a compiled template for a render only copies the
+/// nodes, without reading the body again.
+#[derive(Debug)]
+pub(crate) struct Parsed {
+    /// The nodes with the text in them left empty
+    nodes: Vec<Node<'static>>,
+    /// The text of the nodes, in the order [`Node::map_text`] visits it
+    text: Vec<Text>,
+    sub_templates: HashMap<String, Parsed>,
+    html_scan: Arc<OnceLock<Scan>>,
+}
+
+#[derive(Debug)]
+enum Text {
+    /// Where the text is in the body
+    Body(Range<usize>),
+    /// Text that is not in the body, like the `default` filter that `{name?}` stands for
+    Other(Box<str>),
+}
+
+impl Text {
+    fn new(body: &str, part: &str) -> Text {
+        let start = (part.as_ptr() as usize).wrapping_sub(body.as_ptr() as usize);
+        if start <= body.len() && part.len() <= body.len() - start {
+            Text::Body(start..start + part.len())
+        } else {
+            Text::Other(part.into())
+        }
+    }
+}
+
+impl Parsed {
+    /// The compiled form of `template`, which has to be the template this was parsed from.
+    pub(crate) fn compile<'a>(&'a self, template: &'a NestedTemplate) -> CompiledTemplate<'a> {
+        let mut text = self.text.iter().map(|text| match text {
+            Text::Body(range) => &template.body[range.clone()],
+            Text::Other(text) => &**text,
+        });
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| node.map_text(&mut |_| text.next().unwrap_or_default()))
+            .collect();
+        let sub_templates = self
+            .sub_templates
+            .iter()
+            .filter_map(|(name, parsed)| {
+                let (name, sub_template) = template.sub_templates.get_key_value(name)?;
+                Some((name.as_str(), parsed.compile(sub_template)))
+            })
+            .collect();
+        CompiledTemplate {
+            template,
+            nodes,
+            sub_templates,
+            html_scan: Arc::clone(&self.html_scan),
+        }
+    }
+}
+
+/// What a placeholder name resolved to.
+#[derive(Clone, Copy)]
+pub(crate) enum Binding<'r> {
+    Value(&'r Value),
+    Template(&'r NestedTemplate),
+    Compiled(&'r CompiledTemplate<'r>),
+    /// A template and the parse of it, which is compiled when it is rendered
+    Parsed(&'r NestedTemplate, &'r Parsed),
+}
+
+impl<'r> Binding<'r> {
+    /// Whether the binding is inserted as is, without escaping.
+    pub(crate) fn is_trusted(&self) -> bool {
+        match self {
+            Binding::Value(value) => matches!(value, Value::Safe(_)),
+            Binding::Template(template) | Binding::Parsed(template, _) => template.safe,
+            Binding::Compiled(compiled) => compiled.template.safe,
+        }
+    }
+
+    // Looks up one segment of a dotted path inside this binding
+    fn get(&self, segment: &str) -> Option<Binding<'r>> {
+        match *self {
+            Binding::Value(Value::Map(map)) => map.get(segment).map(Binding::Value),
+            Binding::Value(Value::List(list)) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| list.get(i))
+                .map(Binding::Value),
+            Binding::Value(_) => None,
+            Binding::Template(template) => {
+                if let Some(value) = template.values.get(segment) {
+                    Some(Binding::Value(value))
+                } else {
+                    template.sub_templates.get(segment).map(Binding::Template)
+                }
+            }
+            Binding::Compiled(compiled) => {
+                if let Some(value) = compiled.template.values.get(segment) {
+                    Some(Binding::Value(value))
+                } else if let Some(child) = compiled.sub_templates.get(segment) {
+                    Some(Binding::Compiled(child))
+                } else {
+                    // Only reached when the template was not compiled with its sub-templates
+                    Binding::Template(compiled.template).get(segment)
+                }
+            }
+            Binding::Parsed(template, parsed) => {
+                if let Some(value) = template.values.get(segment) {
+                    Some(Binding::Value(value))
+                } else {
+                    let sub_template = template.sub_templates.get(segment)?;
+                    match parsed.sub_templates.get(segment) {
+                        Some(parsed) => Some(Binding::Parsed(sub_template, parsed)),
+                        None => Some(Binding::Template(sub_template)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Indents every line after the first of what was written to `out` from `start` to the column it
+// started at, counting from the start of the template's own output. Tabs before the column are
+// kept so the lines line up however they are displayed
+fn indent_lines(out: &mut String, start: usize, output_start: usize) {
+    if !out[start..].contains('\n') {
+        return;
+    }
+    let line_start = out[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    let indent: String = out[line_start.max(output_start)..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    if indent.is_empty() {
+        return;
+    }
+
+    let inserted = out.split_off(start);
+    let mut lines = inserted.split('\n');
+    out.push_str(lines.next().unwrap_or_default());
+    for line in lines {
+        out.push('\n');
+        // Blank lines are left without trailing whitespace
+        if !line.is_empty() && line != "\r" {
+            out.push_str(&indent);
+        }
+        out.push_str(line);
+    }
+}
+
+// Renders the first of `levels`, leaving the rest for `{super}`
+fn render_levels(levels: &[Level], out: &mut String, state: State) -> Result<(), ParseError> {
+    match levels.split_first() {
+        Some((level, rest)) => {
+            let state = State {
+                supers: rest,
+                html: level.html,
+                ..state
+            };
+            level.template.render_nodes(level.nodes, out, state)
+        }
+        None => Ok(()),
+    }
+}
+
+// The body of the block called `name`, wherever it is in `nodes`
+fn find_block<'n, 'a>(nodes: &'n [Node<'a>], name: &str) -> Option<&'n [Node<'a>]> {
+    nodes.iter().find_map(|node| match node {
+        Node::Block { name: found, body } if *found == name => Some(&body[..]),
+        Node::Block { body, .. } => find_block(body, name),
+        Node::If {
+            then, otherwise, ..
+        } => find_block(then, name).or_else(|| find_block(otherwise, name)),
+        Node::Each { body, empty, .. } => {
+            find_block(body, name).or_else(|| find_block(empty, name))
+        }
+        _ => None,
+    })
+}
+
+/// One version of a block: the nodes of its body and the template they belong to.
+#[derive(Clone, Copy)]
+pub(crate) struct Level<'c> {
+    template: &'c CompiledTemplate<'c>,
+    nodes: &'c [Node<'c>],
+    /// Where the placeholders in the nodes sit in the markup of the template that places them
+    html: Option<&'c Scan>,
+}
+
+/// The templates that extend the one being rendered, linked from the least derived.
+pub(crate) struct Overrides<'c> {
+    level: Level<'c>,
+    parent: Option<&'c Overrides<'c>>,
+}
+
+/// The templates being rendered, linked from the innermost one out.
+pub(crate) struct Include<'c> {
+    template: &'c NestedTemplate,
+    /// Whether the template is rendered as the base of the one before it rather than inserted
+    extends: bool,
+    parent: Option<&'c Include<'c>>,
+}
+
+impl Include<'_> {
+    // Fails if this template is already being rendered further out, or if it is nested deeper
+    // than `max_depth` when a maximum is set, which allows templates to include themselves. A
+    // base is only repeated when it is reached again through nothing but `{% extends %}`, since
+    // templates inserted in different places may well share a layout
+    fn check(&self, max_depth: Option<usize>) -> Result<(), ParseError> {
+        let (mut depth, mut repeated) = (1, false);
+        // Whether every template from this one out to `outer` extends the one after it
+        let mut extending = self.extends;
+        let mut include = self.parent;
+        while let Some(outer) = include {
+            depth += 1;
+            if std::ptr::eq(outer.template, self.template) {
+                repeated |= match self.extends {
+                    true => extending,
+                    false => !outer.extends,
+                };
+            }
+            extending &= outer.extends;
+            include = outer.parent;
+        }
+
+        let failed = match max_depth {
+            Some(max_depth) => depth > max_depth,
+            None => repeated,
+        };
+        if !failed {
+            return Ok(());
+        }
+
+        let mut names = Vec::with_capacity(depth);
+        let mut include = Some(self);
+        while let Some(Include {
+            template, parent, ..
+        }) = include
+        {
+            names.push(template.name().unwrap_or("(unnamed)").to_string());
+            include = *parent;
+        }
+        names.reverse();
+        Err(match max_depth {
+            Some(max_depth) => ParseError::TooDeep(max_depth, names),
+            None => ParseError::Cycle(names),
+        })
+    }
+}
+
+/// A name bound while rendering, such as the item of an `{#each}` loop. Locals are visible to
+/// every sub-template rendered inside the block that binds them.
+pub(crate) struct Local<'c> {
+    name: &'c str,
+    binding: Binding<'c>,
+    parent: Option<&'c Local<'c>>,
+}
+
+/// Per-render settings that are handed down from a template to the sub-templates it inserts.
+#[derive(Clone, Copy)]
+pub(crate) struct State<'c> {
+    pub(crate) ctx: &'c Context,
+    pub(crate) escape: Escape,
+    /// Whether multi-line output inserted by a placeholder is indented to its column
+    indent: bool,
+    /// Where the output of the template being rendered starts in the string it is written to
+    output_start: usize,
+    locals: Option<&'c Local<'c>>,
+    filters: Option<&'c FilterScope<'c>>,
+    includes: Option<&'c Include<'c>>,
+    overrides: Option<&'c Overrides<'c>>,
+    /// The templates every template being rendered can use once nothing closer has the name
+    registry: Option<&'c Registry>,
+    /// What `{super}` renders in the block being rendered
+    supers: &'c [Level<'c>],
+    /// Where the placeholders being rendered sit in the markup, when they are in a block that
+    /// overrides one in another template
+    html: Option<&'c Scan>,
+}
+
+impl<'c> State<'c> {
+    pub(crate) fn new(ctx: &'c Context) -> State<'c> {
+        State {
+            ctx,
+            escape: Escape::None,
+            indent: false,
+            output_start: 0,
+            locals: None,
+            filters: None,
+            includes: None,
+            overrides: None,
+            registry: None,
+            supers: &[],
+            html: None,
+        }
+    }
+
+    pub(crate) fn with_registry(ctx: &'c Context, registry: &'c Registry) -> State<'c> {
+        State {
+            registry: Some(registry),
+            ..State::new(ctx)
+        }
+    }
+
+    // The state for rendering the body of `template` when it is inserted by this one
+    fn enter(self, template: &NestedTemplate) -> State<'c> {
+        State {
+            escape: template.escape_mode().unwrap_or(self.escape),
+            indent: template.auto_indent.unwrap_or(self.indent),
+            ..self
+        }
+    }
+}
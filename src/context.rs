@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{NestedTemplate, Value};
+
+/// Data for a single render. Placeholders are looked up in the context before the values and
+/// sub-templates registered on the template itself, so one template tree can be shared between
+/// many renders that each bring their own data.
+#[derive(Debug, Default)]
+pub struct Context {
+    values: HashMap<String, Value>,
+    templates: HashMap<String, NestedTemplate>,
+    missing: Missing,
+    max_depth: Option<usize>,
+}
+
+/// Called with the name of a missing placeholder.
+pub type MissingCallback = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// What a placeholder renders as when nothing is bound to its name and it has no default.
+#[derive(Default)]
+pub enum Missing {
+    /// Fail the render with `ParseError::MissingTemplate`
+    #[default]
+    Error,
+    /// Render nothing
+    Empty,
+    /// Leave the placeholder in the output as written, so a later render can fill it in
+    Keep,
+    /// Render whatever the callback returns for the name, escaped like a string value. `None`
+    /// fails the render as `Error` would
+    Callback(Box<MissingCallback>),
+}
+
+impl fmt::Debug for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "Error"),
+            Self::Empty => write!(f, "Empty"),
+            Self::Keep => write!(f, "Keep"),
+            Self::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Binds `name` to a value for this render. Replaces any sub-template registered under the
+    /// same name.
+    pub fn set_value(&mut self, name: &str, value: impl Into<Value>) {
+        self.templates.remove(name);
+        self.values.insert(name.to_string(), value.into());
+    }
+
+    /// Binds `name` to a template for this render. Replaces any value registered under the same
+    /// name.
+    pub fn add_sub_template(&mut self, name: &str, mut template: NestedTemplate) {
+        template.name.get_or_insert_with(|| name.to_string());
+        self.values.remove(name);
+        self.templates.insert(name.to_string(), template);
+    }
+
+    /// Sets what placeholders with nothing bound to them render as. Conditions and loops are not
+    /// affected.
+    pub fn set_missing(&mut self, missing: Missing) {
+        self.missing = missing;
+    }
+
+    /// Allows templates to include themselves, directly or through other templates, as long as
+    /// no more than `max_depth` templates are nested inside each other. Without a maximum, which
+    /// is the default, a template that includes itself fails with `ParseError::Cycle`.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.max_depth = max_depth;
+    }
+
+    pub(crate) fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub(crate) fn missing(&self) -> &Missing {
+        &self.missing
+    }
+
+    pub(crate) fn value(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    pub(crate) fn template(&self, name: &str) -> Option<&NestedTemplate> {
+        self.templates.get(name)
+    }
+}
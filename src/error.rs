@@ -0,0 +1,239 @@
+use std::fmt::Formatter;
+
+/// Where in a template body an error was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// Byte offset into the template body
+    pub offset: usize,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column, counted in characters
+    pub column: usize,
+    /// Name of the template the body belongs to, if it has one
+    pub template: Option<String>,
+    /// The offending line followed by a second line with a caret under the error
+    pub snippet: String,
+}
+
+impl Location {
+    pub(crate) fn new(body: &str, offset: usize) -> Location {
+        let line_start = body[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = body[offset..].find('\n').map_or(body.len(), |i| offset + i);
+        let line_text = body[line_start..line_end].trim_end_matches('\r');
+        let before = &body[line_start..offset];
+
+        // Keep tabs so the caret lines up with the original text in a terminal
+        let padding: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        Location {
+            offset,
+            line: body[..offset].matches('\n').count() + 1,
+            column: before.chars().count() + 1,
+            template: None,
+            snippet: format!("{}\n{}^", line_text, padding),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(template) = &self.template {
+            write!(f, "{}:", template)?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    MissingOpenBrace(Location),
+    MissingCloseBrace(Location),
+    MissingTemplate(String),
+    EmptyPlaceholder(Location),
+    /// The placeholder named by the first field is bound to a value of the kind in the second
+    /// field, such as a list or map, that cannot be inserted as text
+    NotText(String, &'static str),
+    /// The list of an `{#each}` block named by the first field is bound to a value of the kind in
+    /// the second field instead of a list
+    NotList(String, &'static str),
+    /// The template named in `{% extends %}` is bound to a value of the kind in the second field
+    /// instead of a template
+    NotTemplate(String, &'static str),
+    /// A placeholder sits somewhere in an HTML template where inserted text cannot be escaped
+    /// safely, or the text would be unsafe there. The second field says why
+    Unsafe(Location, &'static str),
+    /// A template includes itself, directly or through other templates. Lists the names of the
+    /// templates from the outermost to the repeated one
+    Cycle(Vec<String>),
+    /// Templates are nested more deeply than the maximum in the first field allows. Lists the
+    /// names of the templates from the outermost in
+    TooDeep(usize, Vec<String>),
+    /// No filter with this name is registered on the template or the templates it is inserted into
+    UnknownFilter(String),
+    /// The filter named by the first field returned the error in the second field
+    FilterFailed(String, String),
+    /// The template file at the path in the first field could not be reloaded
+    Reload(String, std::io::Error),
+    /// A block such as `{#if name}`, a `{raw}` region or a `{# comment` is never closed
+    UnclosedBlock(Location),
+    /// A tag such as `{:else}` or `{/if}` that does not belong to an open block, or an unknown
+    /// `{#...}` tag
+    UnexpectedTag(Location),
+}
+
+impl ParseError {
+    /// The position of the error in the template body, if it has one.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::MissingOpenBrace(loc)
+            | Self::MissingCloseBrace(loc)
+            | Self::EmptyPlaceholder(loc)
+            | Self::Unsafe(loc, _)
+            | Self::UnclosedBlock(loc)
+            | Self::UnexpectedTag(loc) => Some(loc),
+            Self::MissingTemplate(_)
+            | Self::NotText(..)
+            | Self::NotList(..)
+            | Self::NotTemplate(..)
+            | Self::Cycle(_)
+            | Self::TooDeep(..)
+            | Self::UnknownFilter(_)
+            | Self::FilterFailed(..)
+            | Self::Reload(..) => None,
+        }
+    }
+
+    // Attaches the name of the template that was being parsed, unless one is already set
+    pub(crate) fn in_template(mut self, name: Option<&str>) -> Self {
+        if let Some(name) = name {
+            if let Some(loc) = self.location_mut() {
+                loc.template.get_or_insert_with(|| name.to_string());
+            }
+        }
+        self
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Location> {
+        match self {
+            Self::MissingOpenBrace(loc)
+            | Self::MissingCloseBrace(loc)
+            | Self::EmptyPlaceholder(loc)
+            | Self::Unsafe(loc, _)
+            | Self::UnclosedBlock(loc)
+            | Self::UnexpectedTag(loc) => Some(loc),
+            Self::MissingTemplate(_)
+            | Self::NotText(..)
+            | Self::NotList(..)
+            | Self::NotTemplate(..)
+            | Self::Cycle(_)
+            | Self::TooDeep(..)
+            | Self::UnknownFilter(_)
+            | Self::FilterFailed(..)
+            | Self::Reload(..) => None,
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingTemplate(name) => write!(
+                f,
+                "sub_templates does not have any template indexed under: {}",
+                name
+            ),
+            Self::MissingCloseBrace(loc) => write!(
+                f,
+                "Open brace at {} does not have a corresponding close brace\n{}",
+                loc, loc.snippet
+            ),
+            Self::MissingOpenBrace(loc) => write!(
+                f,
+                "Close brace at {} does not have a corresponding open brace\n{}",
+                loc, loc.snippet
+            ),
+            Self::NotText(name, kind) => write!(
+                f,
+                "{} is bound to a {} value, which cannot be rendered as text",
+                name, kind
+            ),
+            Self::NotList(name, kind) => write!(
+                f,
+                "{} is bound to a {} value, which cannot be looped over",
+                name, kind
+            ),
+            Self::NotTemplate(name, kind) => write!(
+                f,
+                "{} is bound to a {} value, which cannot be extended",
+                name, kind
+            ),
+            Self::Cycle(chain) => write!(f, "Template includes itself: {}", chain.join(" -> ")),
+            Self::TooDeep(max_depth, chain) => write!(
+                f,
+                "Templates are nested more than {} deep: {}",
+                max_depth,
+                chain.join(" -> ")
+            ),
+            Self::Reload(path, err) => write!(f, "Could not reload {}: {}", path, err),
+            Self::UnknownFilter(name) => write!(f, "No filter named {} is registered", name),
+            Self::FilterFailed(name, message) => write!(f, "Filter {} failed: {}", name, message),
+            Self::Unsafe(loc, reason) => write!(
+                f,
+                "Template at {} cannot be inserted safely: {}\n{}",
+                loc, reason, loc.snippet
+            ),
+            Self::UnclosedBlock(loc) => write!(
+                f,
+                "Block opened at {} is never closed\n{}",
+                loc, loc.snippet
+            ),
+            Self::UnexpectedTag(loc) => write!(
+                f,
+                "Tag at {} does not match any open block\n{}",
+                loc, loc.snippet
+            ),
+            Self::EmptyPlaceholder(loc) => {
+                write!(
+                    f,
+                    "Template at {} does not have a name\n{}",
+                    loc, loc.snippet
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod location_tests {
+    use super::*;
+
+    #[test]
+    fn test_location_first_line() {
+        let loc = Location::new("abc }", 4);
+        assert_eq!((loc.offset, loc.line, loc.column), (4, 1, 5));
+        assert_eq!(loc.snippet, "abc }\n    ^");
+        assert_eq!(loc.to_string(), "1:5");
+    }
+
+    #[test]
+    fn test_location_later_line() {
+        let loc = Location::new("first\r\n\tsé {\nlast", 12);
+        assert_eq!((loc.line, loc.column), (2, 5));
+        assert_eq!(loc.snippet, "\tsé {\n\t   ^");
+    }
+
+    #[test]
+    fn test_error_display_names_template() {
+        let err =
+            ParseError::MissingOpenBrace(Location::new("a\nb }", 4)).in_template(Some("page"));
+        assert_eq!(
+            err.to_string(),
+            "Close brace at page:2:3 does not have a corresponding open brace\nb }\n  ^"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Write;
+
+/// How text inserted into a template is escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Escape {
+    /// Insert text exactly as it is
+    #[default]
+    None,
+    /// Escape `&`, `<`, `>`, `"` and `'` as HTML entities. When rendering, templates in this mode
+    /// pick the escaper for each placeholder based on where in the markup it sits
+    Html,
+    /// Percent-encode everything except unreserved URL characters
+    Url,
+    /// Escape for use inside a quoted JavaScript string literal
+    JsString,
+    /// Escape for use inside a CSS string or identifier
+    Css,
+}
+
+impl Escape {
+    pub fn escape(self, text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        self.escape_into(text, &mut escaped);
+        escaped
+    }
+
+    pub(crate) fn escape_into(self, text: &str, out: &mut String) {
+        match self {
+            Escape::None => out.push_str(text),
+            Escape::Html => {
+                for c in text.chars() {
+                    match c {
+                        '&' => out.push_str("&amp;"),
+                        '<' => out.push_str("&lt;"),
+                        '>' => out.push_str("&gt;"),
+                        '"' => out.push_str("&quot;"),
+                        '\'' => out.push_str("&#x27;"),
+                        _ => out.push(c),
+                    }
+                }
+            }
+            Escape::Url => {
+                for byte in text.bytes() {
+                    match byte {
+                        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                            out.push(byte as char)
+                        }
+                        _ => {
+                            let _ = write!(out, "%{:02X}", byte);
+                        }
+                    }
+                }
+            }
+            Escape::JsString => {
+                for c in text.chars() {
+                    match c {
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        // Quotes and the HTML specials so the string can't end the literal or the
+                        // surrounding <script> element
+                        '"' | '\'' | '`' | '<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+                            let _ = write!(out, "\\u{:04X}", c as u32);
+                        }
+                        c if c.is_control() => {
+                            let _ = write!(out, "\\u{:04X}", c as u32);
+                        }
+                        _ => out.push(c),
+                    }
+                }
+            }
+            Escape::Css => {
+                for c in text.chars() {
+                    if c.is_ascii_alphanumeric() || !c.is_ascii() {
+                        out.push(c);
+                    } else {
+                        // The trailing space ends the escape so a following hex digit is kept
+                        let _ = write!(out, "\\{:X} ", c as u32);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod escape_tests {
+    use super::*;
+
+    #[test]
+    fn test_html() {
+        assert_eq!(
+            Escape::Html.escape("<a href=\"x\">Tom & 'Jerry'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#x27;Jerry&#x27;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        assert_eq!(Escape::Url.escape("a b/c?d=é"), "a%20b%2Fc%3Fd%3D%C3%A9");
+    }
+
+    #[test]
+    fn test_js_string() {
+        assert_eq!(
+            Escape::JsString.escape("</script>\"\\\n"),
+            "\\u003C/script\\u003E\\u0022\\\\\\n"
+        );
+    }
+
+    #[test]
+    fn test_css() {
+        assert_eq!(Escape::Css.escape("red;}a"), "red\\3B \\7D a");
+    }
+
+    #[test]
+    fn test_none() {
+        assert_eq!(Escape::None.escape("<b>"), "<b>");
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use crate::{Arg, Escape};
+
+/// A filter that can be registered with [`NestedTemplate::register_filter`]. It is called with
+/// the text being filtered and the arguments written in the template, and an error message fails
+/// the render with `ParseError::FilterFailed`.
+///
+/// [`NestedTemplate::register_filter`]: crate::NestedTemplate::register_filter
+pub type FilterFn = dyn Fn(&str, &[Arg]) -> Result<String, String> + Send + Sync;
+
+/// The filters registered on one template.
+#[derive(Default)]
+pub(crate) struct Filters(HashMap<String, Box<FilterFn>>);
+
+impl Filters {
+    pub(crate) fn insert(&mut self, name: &str, filter: Box<FilterFn>) {
+        self.0.insert(name.to_string(), filter);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&FilterFn> {
+        self.0.get(name).map(|filter| &**filter)
+    }
+}
+
+impl fmt::Debug for Filters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// The filters of the templates from the one being rendered up to the root, so a filter
+/// registered on a template can be used by all of its sub-templates.
+#[derive(Clone, Copy)]
+pub(crate) struct FilterScope<'c> {
+    pub(crate) filters: &'c Filters,
+    pub(crate) parent: Option<&'c FilterScope<'c>>,
+}
+
+impl<'c> FilterScope<'c> {
+    /// The nearest registered filter called `name`, or the built-in one.
+    pub(crate) fn get(scope: Option<&'c FilterScope<'c>>, name: &str) -> Option<&'c FilterFn> {
+        let mut scope = scope;
+        while let Some(FilterScope { filters, parent }) = scope {
+            if let Some(filter) = filters.get(name) {
+                return Some(filter);
+            }
+            scope = *parent;
+        }
+        builtin(name)
+    }
+
+    /// Whether `name` can be used in a template rendered with this scope.
+    pub(crate) fn is_known(scope: Option<&'c FilterScope<'c>>, name: &str) -> bool {
+        // These need to know how the placeholder is inserted, so the renderer applies them
+        matches!(name, "default" | "escape" | "safe") || FilterScope::get(scope, name).is_some()
+    }
+}
+
+fn builtin(name: &str) -> Option<&'static FilterFn> {
+    let filter: &'static FilterFn = match name {
+        "upper" => &|text, args| no_args(args).map(|_| text.to_uppercase()),
+        "lower" => &|text, args| no_args(args).map(|_| text.to_lowercase()),
+        "trim" => &|text, args| no_args(args).map(|_| text.trim().to_string()),
+        "truncate" => &truncate,
+        "urlencode" => &|text, args| no_args(args).map(|_| Escape::Url.escape(text)),
+        "json" => &|text, args| no_args(args).map(|_| json_string(text)),
+        "date" => &date,
+        _ => return None,
+    };
+    Some(filter)
+}
+
+fn no_args(args: &[Arg]) -> Result<(), String> {
+    match args {
+        [] => Ok(()),
+        _ => Err("takes no arguments".to_string()),
+    }
+}
+
+// `truncate(length)` or `truncate(length, "end")`. The end, `...` by default, is only added when
+// something was cut off
+fn truncate(text: &str, args: &[Arg]) -> Result<String, String> {
+    let (length, end) = match args {
+        [length] => (length.as_int(), Some("...")),
+        [length, end] => (length.as_int(), end.as_str()),
+        _ => (None, None),
+    };
+    let (Some(length), Some(end)) = (length, end) else {
+        return Err("expects a length and an optional end string".to_string());
+    };
+    let length = usize::try_from(length).map_err(|_| "length must not be negative")?;
+
+    match text.char_indices().nth(length) {
+        Some((cut, _)) => Ok(format!("{}{}", &text[..cut], end)),
+        None => Ok(text.to_string()),
+    }
+}
+
+/// `text` as a JSON string literal, quotes included.
+pub(crate) fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+// `date` or `date("format")`. The text is either a Unix timestamp in seconds or an ISO 8601 date
+// like `2024-03-05` or `2024-03-05T14:30:00`, always read as UTC. The format supports `%Y`, `%y`,
+// `%m`, `%d`, `%e`, `%H`, `%M`, `%S`, `%b`, `%B`, `%a`, `%A` and `%%`, and defaults to `%Y-%m-%d`
+fn date(text: &str, args: &[Arg]) -> Result<String, String> {
+    let format = match args {
+        [] => "%Y-%m-%d",
+        [format] => format.as_str().ok_or("the format must be a string")?,
+        _ => return Err("takes at most one format".to_string()),
+    };
+    let date = DateTime::parse(text.trim()).ok_or_else(|| format!("{:?} is not a date", text))?;
+
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let _ = match chars.next() {
+            Some('Y') => write!(out, "{}", date.year),
+            Some('y') => write!(out, "{:02}", date.year.rem_euclid(100)),
+            Some('m') => write!(out, "{:02}", date.month),
+            Some('d') => write!(out, "{:02}", date.day),
+            Some('e') => write!(out, "{}", date.day),
+            Some('H') => write!(out, "{:02}", date.hour),
+            Some('M') => write!(out, "{:02}", date.minute),
+            Some('S') => write!(out, "{:02}", date.second),
+            Some('B') => write!(out, "{}", MONTHS[date.month as usize - 1]),
+            Some('b') => write!(out, "{}", &MONTHS[date.month as usize - 1][..3]),
+            Some('A') => write!(out, "{}", WEEKDAYS[date.weekday()]),
+            Some('a') => write!(out, "{}", &WEEKDAYS[date.weekday()][..3]),
+            Some('%') => write!(out, "%"),
+            Some(other) => return Err(format!("unknown format %{}", other)),
+            None => return Err("format ends in %".to_string()),
+        };
+    }
+    Ok(out)
+}
+
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    fn parse(text: &str) -> Option<DateTime> {
+        if let Ok(timestamp) = text.parse::<i64>() {
+            let days = timestamp.div_euclid(86_400);
+            let seconds = timestamp.rem_euclid(86_400) as u32;
+            let (year, month, day) = civil_from_days(days);
+            return Some(DateTime {
+                year,
+                month,
+                day,
+                hour: seconds / 3600,
+                minute: seconds / 60 % 60,
+                second: seconds % 60,
+            });
+        }
+
+        let (date, time) = match text.split_once(['T', ' ']) {
+            Some((date, time)) => (date, Some(time)),
+            None => (text, None),
+        };
+        let mut parts = date.splitn(3, '-');
+        // Four digit years keep the day arithmetic well inside an i64
+        let year = parts
+            .next()?
+            .parse()
+            .ok()
+            .filter(|y| (0..=9999).contains(y))?;
+        let month = parts
+            .next()?
+            .parse()
+            .ok()
+            .filter(|m| (1..=12).contains(m))?;
+        let day = parts
+            .next()?
+            .parse()
+            .ok()
+            .filter(|d| (1..=31).contains(d))?;
+
+        // Anything after the seconds, like a fraction or an offset, is ignored
+        let (mut hour, mut minute, mut second) = (0, 0, 0);
+        if let Some(time) = time {
+            let mut parts = time.splitn(3, ':');
+            hour = parts.next()?.parse().ok().filter(|h| *h < 24)?;
+            minute = parts.next()?.parse().ok().filter(|m| *m < 60)?;
+            if let Some(rest) = parts.next() {
+                let digits = rest.get(..2)?;
+                second = digits.parse().ok().filter(|s| *s < 61)?;
+            }
+        }
+
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    // 0 for Monday
+    fn weekday(&self) -> usize {
+        // 1970-01-01 was a Thursday
+        (days_from_civil(self.year, self.month, self.day) + 3).rem_euclid(7) as usize
+    }
+}
+
+// Howard Hinnant's conversions between days since 1970-01-01 and proleptic Gregorian dates
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use super::*;
+
+    fn apply(name: &str, text: &str, args: &[Arg]) -> Result<String, String> {
+        builtin(name).unwrap()(text, args)
+    }
+
+    #[test]
+    fn test_text_filters() {
+        assert_eq!(apply("upper", "Straße", &[]).unwrap(), "STRASSE");
+        assert_eq!(apply("lower", "ÀB", &[]).unwrap(), "àb");
+        assert_eq!(apply("trim", "  a b \n", &[]).unwrap(), "a b");
+        assert_eq!(apply("urlencode", "a b/ü", &[]).unwrap(), "a%20b%2F%C3%BC");
+        assert_eq!(
+            apply("json", "say \"hi\"\n\u{1}", &[]).unwrap(),
+            "\"say \\\"hi\\\"\\n\\u0001\""
+        );
+        assert!(apply("upper", "a", &[Arg::Int(1)]).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(apply("truncate", "héllo", &[Arg::Int(5)]).unwrap(), "héllo");
+        assert_eq!(apply("truncate", "héllo", &[Arg::Int(2)]).unwrap(), "hé...");
+        assert_eq!(
+            apply("truncate", "hello", &[Arg::Int(0), Arg::Str("…")]).unwrap(),
+            "…"
+        );
+        assert!(apply("truncate", "hello", &[]).is_err());
+        assert!(apply("truncate", "hello", &[Arg::Int(-1)]).is_err());
+        assert!(apply("truncate", "hello", &[Arg::Str("2")]).is_err());
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(apply("date", "0", &[]).unwrap(), "1970-01-01");
+        assert_eq!(
+            apply("date", "1709649000", &[Arg::Str("%a %e %b %y %H:%M:%S")]).unwrap(),
+            "Tue 5 Mar 24 14:30:00"
+        );
+        assert_eq!(
+            apply(
+                "date",
+                "2000-02-29T23:59:59.5Z",
+                &[Arg::Str("%A %d %B %Y, %H%%")]
+            )
+            .unwrap(),
+            "Tuesday 29 February 2000, 23%"
+        );
+        assert_eq!(apply("date", "-1", &[]).unwrap(), "1969-12-31");
+        assert!(apply("date", "2024-13-01", &[]).is_err());
+        assert!(apply("date", "99999999999999999-01-01", &[Arg::Str("%A")]).is_err());
+        assert!(apply("date", "yesterday", &[]).is_err());
+        assert!(apply("date", "0", &[Arg::Str("%q")]).is_err());
+    }
+}
@@ -0,0 +1,911 @@
+use crate::parser::offset_in;
+use crate::{Escape, Node};
+
+/// Where in an HTML document a placeholder sits, which decides how text inserted there has to be
+/// escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HtmlContext {
+    /// Between tags
+    Text,
+    /// Inside a quoted attribute value
+    Attribute,
+    /// Inside an unquoted attribute value
+    UnquotedAttribute,
+    /// Inside the value of an attribute such as `href` or `src`. `start` is set when nothing comes
+    /// before the placeholder in the value, so it decides the scheme of the URL
+    Url { start: bool, quoted: bool },
+    /// Inside a string literal in a `<script>` element
+    Script,
+    /// Inside a template literal in a `<script>` element, where `${` would start code
+    ScriptTemplate,
+    /// Inside a `<style>` element or a `style` attribute
+    Style,
+    /// Somewhere no text can be inserted safely, with the reason why
+    Unsafe(&'static str),
+}
+
+impl HtmlContext {
+    pub(crate) fn escape_into(self, text: &str, out: &mut String) -> Result<(), &'static str> {
+        match self {
+            HtmlContext::Text | HtmlContext::Attribute => Escape::Html.escape_into(text, out),
+            HtmlContext::UnquotedAttribute => escape_unquoted_attribute(text, out),
+            HtmlContext::Url {
+                start: true,
+                quoted,
+            } => {
+                if !has_safe_scheme(text) {
+                    return Err("URL scheme other than http, https or mailto");
+                }
+                if quoted {
+                    Escape::Html.escape_into(text, out);
+                } else {
+                    escape_unquoted_attribute(text, out);
+                }
+            }
+            HtmlContext::Url { start: false, .. } => Escape::Url.escape_into(text, out),
+            HtmlContext::Script => Escape::JsString.escape_into(text, out),
+            HtmlContext::ScriptTemplate => {
+                // String escaping never writes a `$` of its own
+                out.push_str(&Escape::JsString.escape(text).replace('$', "\\u0024"));
+            }
+            HtmlContext::Style => Escape::Css.escape_into(text, out),
+            HtmlContext::Unsafe(reason) => return Err(reason),
+        }
+
+        Ok(())
+    }
+}
+
+// Unquoted attribute values end at whitespace, so everything but letters and digits is encoded
+fn escape_unquoted_attribute(text: &str, out: &mut String) {
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || !c.is_ascii() {
+            out.push(c);
+        } else {
+            out.push_str(&format!("&#x{:X};", c as u32));
+        }
+    }
+}
+
+fn has_safe_scheme(url: &str) -> bool {
+    let url = url.trim_start();
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url.as_bytes()[i] == b':' => {
+            let scheme = url[..i].to_ascii_lowercase();
+            matches!(scheme.as_str(), "http" | "https" | "mailto")
+        }
+        // No scheme, so a relative URL
+        _ => true,
+    }
+}
+
+const URL_ATTRIBUTES: [&str; 15] = [
+    "href",
+    "xlink:href",
+    "src",
+    "srcset",
+    "action",
+    "formaction",
+    "cite",
+    "poster",
+    "background",
+    "longdesc",
+    "usemap",
+    "data",
+    "codebase",
+    "manifest",
+    "ping",
+];
+
+/// The [`HtmlContext`] of every placeholder in some nodes, and where in the markup each
+/// `{block}` among them starts so the blocks that override it can be placed there.
+#[derive(Debug, Default)]
+pub(crate) struct Scan {
+    /// By the offset of the name of the placeholder in the body, sorted by it
+    placeholders: Vec<(usize, HtmlContext)>,
+    /// By the offset of the name of the block and the name itself
+    blocks: Vec<(usize, String, Scanner)>,
+}
+
+impl Scan {
+    pub(crate) fn context(&self, offset: usize) -> HtmlContext {
+        match self
+            .placeholders
+            .binary_search_by_key(&offset, |(offset, _)| *offset)
+        {
+            Ok(i) => self.placeholders[i].1,
+            Err(_) => HtmlContext::Unsafe("placeholder outside of the template body"),
+        }
+    }
+
+    /// Scans `nodes`, from `body`, as if they were written where the block with its name at
+    /// `offset` starts.
+    pub(crate) fn block_at(&self, offset: usize, body: &str, nodes: &[Node]) -> Option<Scan> {
+        let (_, _, start) = self.blocks.iter().find(|(at, ..)| *at == offset)?;
+        Some(scan(body, nodes, start.clone()))
+    }
+
+    /// Like [`block_at`](Scan::block_at), for the first block called `name`.
+    pub(crate) fn block_named(&self, name: &str, body: &str, nodes: &[Node]) -> Option<Scan> {
+        let (_, _, start) = self.blocks.iter().find(|(_, found, _)| found == name)?;
+        Some(scan(body, nodes, start.clone()))
+    }
+}
+
+/// Works out the [`HtmlContext`] of every placeholder by running the literal text of the template
+/// through a small HTML tokenizer. Placeholders are identified by the offset of their name in
+/// `body`.
+pub(crate) fn contexts(body: &str, nodes: &[Node]) -> Scan {
+    scan(body, nodes, Scanner::new())
+}
+
+fn scan(body: &str, nodes: &[Node], mut scanner: Scanner) -> Scan {
+    let mut found = Scan::default();
+    scan_nodes(body, nodes, &mut scanner, &mut found);
+    found.placeholders.sort_by_key(|(offset, _)| *offset);
+    found
+}
+
+// How many times the body of an `{#each}` is scanned looking for where its items can start
+const EACH_PASSES: usize = 4;
+
+fn scan_nodes(body: &str, nodes: &[Node], scanner: &mut Scanner, found: &mut Scan) {
+    for node in nodes {
+        match node {
+            Node::Literal(text) => text.chars().for_each(|c| scanner.feed(c)),
+            Node::Placeholder { name, .. } => {
+                let offset = offset_in(body, name);
+                found.placeholders.push((offset, scanner.context()));
+                scanner.inserted();
+            }
+            Node::If {
+                then, otherwise, ..
+            } => {
+                // Both branches start where the block does. What follows could come after either
+                let mut alternative = scanner.clone();
+                scan_nodes(body, otherwise, &mut alternative, found);
+                scan_nodes(body, then, scanner, found);
+                scanner.join(alternative);
+            }
+            Node::Block { name, body: nodes } => {
+                let offset = offset_in(body, name);
+                found
+                    .blocks
+                    .push((offset, name.to_string(), scanner.clone()));
+                scan_nodes(body, nodes, scanner, found);
+            }
+            Node::Super => scanner.inserted(),
+            Node::Extends(_) => (),
+            Node::Each {
+                body: nodes, empty, ..
+            } => {
+                // The first item starts where the loop does and every other one where the item
+                // before it ended. Scan the body from every place it can start until it ends in
+                // one of them, or give up
+                let start = scanner.clone();
+                let mut entry = start.clone();
+                let mut items = Scan::default();
+                for pass in 1.. {
+                    *scanner = entry.clone();
+                    items = Scan::default();
+                    scan_nodes(body, nodes, scanner, &mut items);
+                    if entry.covers(scanner) {
+                        break;
+                    }
+                    if pass == EACH_PASSES {
+                        *scanner = start.clone();
+                        scanner.lost = true;
+                        items = Scan::default();
+                        scan_nodes(body, nodes, scanner, &mut items);
+                        break;
+                    }
+                    entry.join(scanner.clone());
+                }
+                found.placeholders.extend(items.placeholders);
+                found.blocks.extend(items.blocks);
+
+                // Without any items, `{:empty}` is written instead
+                let mut alternative = start;
+                scan_nodes(body, empty, &mut alternative, found);
+                scanner.join(alternative);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    TagName,
+    BeforeAttrName,
+    AttrName,
+    AfterAttrName,
+    BeforeAttrValue,
+    AttrValue {
+        quote: Option<char>,
+        empty: bool,
+    },
+    Comment,
+    /// The content of a `<script>` or `<style>` element
+    RawText {
+        script: bool,
+    },
+}
+
+/// Where in the code of a `<script>` element the scanner is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Js {
+    /// Outside of strings and comments. `regex` is set when a `/` here would start a regular
+    /// expression rather than divide
+    Code {
+        regex: bool,
+    },
+    /// After a `/` in code, before it is known what it starts
+    Slash {
+        regex: bool,
+    },
+    /// Inside a string literal quoted with `quote`, which is a backtick for template literals
+    String {
+        quote: char,
+        escaped: bool,
+    },
+    LineComment,
+    BlockComment {
+        star: bool,
+    },
+    Regex {
+        class: bool,
+        escaped: bool,
+    },
+}
+
+// Words after which a `/` starts a regular expression, like `return /a/`
+const JS_KEYWORDS: [&str; 14] = [
+    "await",
+    "case",
+    "delete",
+    "do",
+    "else",
+    "in",
+    "instanceof",
+    "new",
+    "return",
+    "throw",
+    "typeof",
+    "void",
+    "yield",
+    "of",
+];
+
+impl Js {
+    // The next state for `c` inside a regular expression literal
+    fn regex(self, c: char) -> Js {
+        let Js::Regex { class, escaped } = self else {
+            return self;
+        };
+        match c {
+            _ if escaped => Js::Regex {
+                class,
+                escaped: false,
+            },
+            '\\' => Js::Regex {
+                class,
+                escaped: true,
+            },
+            '[' => Js::Regex {
+                class: true,
+                escaped: false,
+            },
+            ']' => Js::Regex {
+                class: false,
+                escaped: false,
+            },
+            '/' if !class => Js::Code { regex: false },
+            _ => self,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Scanner {
+    state: State,
+    tag: String,
+    closing: bool,
+    attr: String,
+    js: Js,
+    /// The identifier or keyword the script code ends with so far
+    js_word: String,
+    /// For each `${` of a template literal that is still open, how many braces are open in it
+    js_substitutions: Vec<usize>,
+    /// The last few characters, lowercased, to spot `-->` and `</script`
+    recent: String,
+    /// The other places the scanner could be, after blocks that can end in different places.
+    /// Each of them is scanned alongside until it ends up in the same place as this one
+    alternatives: Vec<Scanner>,
+    /// Set when the scanner can no longer tell where in the markup it is
+    lost: bool,
+}
+
+impl Scanner {
+    fn new() -> Scanner {
+        Scanner {
+            state: State::Data,
+            tag: String::new(),
+            closing: false,
+            attr: String::new(),
+            js: Js::Code { regex: true },
+            js_word: String::new(),
+            js_substitutions: Vec::new(),
+            recent: String::new(),
+            alternatives: Vec::new(),
+            lost: false,
+        }
+    }
+
+    fn feed(&mut self, c: char) {
+        for alternative in &mut self.alternatives {
+            alternative.step(c);
+        }
+        self.step(c);
+        self.converge();
+    }
+
+    fn step(&mut self, c: char) {
+        if self.recent.len() >= 8 {
+            self.recent.remove(0);
+        }
+        self.recent.push(c.to_ascii_lowercase());
+
+        self.state = match self.state {
+            State::Data if c == '<' => {
+                self.tag.clear();
+                self.closing = false;
+                State::TagName
+            }
+            State::Data => State::Data,
+            State::TagName => {
+                if c == '/' && self.tag.is_empty() {
+                    self.closing = true;
+                    State::TagName
+                } else if c.is_ascii_alphanumeric() || (c == '!' || c == '-') {
+                    self.tag.push(c.to_ascii_lowercase());
+                    if self.tag == "!--" {
+                        State::Comment
+                    } else {
+                        State::TagName
+                    }
+                } else if self.tag.is_empty() {
+                    // Something like `a < b`, which is not a tag at all
+                    State::Data
+                } else if c == '>' {
+                    self.end_tag()
+                } else {
+                    State::BeforeAttrName
+                }
+            }
+            State::BeforeAttrName => match c {
+                '>' => self.end_tag(),
+                c if c.is_whitespace() || c == '/' => State::BeforeAttrName,
+                c => self.start_attr(c),
+            },
+            State::AttrName => match c {
+                '=' => State::BeforeAttrValue,
+                '>' => self.end_tag(),
+                '/' => State::BeforeAttrName,
+                c if c.is_whitespace() => State::AfterAttrName,
+                c => {
+                    self.attr.push(c.to_ascii_lowercase());
+                    State::AttrName
+                }
+            },
+            State::AfterAttrName => match c {
+                '=' => State::BeforeAttrValue,
+                '>' => self.end_tag(),
+                c if c.is_whitespace() => State::AfterAttrName,
+                c => self.start_attr(c),
+            },
+            State::BeforeAttrValue => match c {
+                '"' | '\'' => State::AttrValue {
+                    quote: Some(c),
+                    empty: true,
+                },
+                '>' => self.end_tag(),
+                c if c.is_whitespace() => State::BeforeAttrValue,
+                _ => State::AttrValue {
+                    quote: None,
+                    empty: false,
+                },
+            },
+            State::AttrValue { quote: Some(q), .. } if c == q => State::BeforeAttrName,
+            State::AttrValue { quote: None, .. } if c.is_whitespace() => State::BeforeAttrName,
+            State::AttrValue { quote: None, .. } if c == '>' => self.end_tag(),
+            State::AttrValue { quote, .. } => State::AttrValue {
+                quote,
+                empty: false,
+            },
+            State::Comment if self.recent.ends_with("-->") => State::Data,
+            State::Comment => State::Comment,
+            State::RawText { script } => self.raw_text(script, c),
+        };
+    }
+
+    fn start_attr(&mut self, c: char) -> State {
+        self.attr.clear();
+        self.attr.push(c.to_ascii_lowercase());
+        State::AttrName
+    }
+
+    fn end_tag(&mut self) -> State {
+        match self.tag.as_str() {
+            "script" if !self.closing => {
+                self.js = Js::Code { regex: true };
+                self.js_word.clear();
+                self.js_substitutions.clear();
+                State::RawText { script: true }
+            }
+            "style" if !self.closing => State::RawText { script: false },
+            _ => State::Data,
+        }
+    }
+
+    fn raw_text(&mut self, script: bool, c: char) -> State {
+        let end = if script { "</script" } else { "</style" };
+        if self.recent.ends_with(end) {
+            // The closing tag ends the element even inside a string literal
+            self.tag = end[2..].to_string();
+            self.closing = true;
+            return State::TagName;
+        }
+
+        if script {
+            self.js = self.script(c);
+        }
+
+        State::RawText { script }
+    }
+
+    fn script(&mut self, c: char) -> Js {
+        match self.js {
+            Js::Code { regex } => self.code(regex, c),
+            Js::Slash { .. } if c == '/' => Js::LineComment,
+            Js::Slash { .. } if c == '*' => Js::BlockComment { star: false },
+            Js::Slash { regex: true } => Js::Regex {
+                class: false,
+                escaped: false,
+            }
+            .regex(c),
+            // A division, so whatever comes next is an operand
+            Js::Slash { regex: false } => self.code(true, c),
+            Js::String { quote, escaped } => match c {
+                _ if escaped => Js::String {
+                    quote,
+                    escaped: false,
+                },
+                '\\' => Js::String {
+                    quote,
+                    escaped: true,
+                },
+                '{' if quote == '`' && self.recent.ends_with("${") => {
+                    self.js_substitutions.push(0);
+                    Js::Code { regex: true }
+                }
+                c if c == quote => Js::Code { regex: false },
+                _ => self.js,
+            },
+            Js::LineComment if matches!(c, '\n' | '\r' | '\u{2028}' | '\u{2029}') => {
+                Js::Code { regex: true }
+            }
+            Js::LineComment => Js::LineComment,
+            Js::BlockComment { star: true } if c == '/' => Js::Code { regex: true },
+            Js::BlockComment { .. } => Js::BlockComment { star: c == '*' },
+            Js::Regex { .. } => self.js.regex(c),
+        }
+    }
+
+    // The next state for `c` in code, outside of strings and comments
+    fn code(&mut self, regex: bool, c: char) -> Js {
+        if c.is_whitespace() {
+            self.js_word.clear();
+            return Js::Code { regex };
+        }
+        if c.is_alphanumeric() || c == '_' || c == '$' {
+            self.js_word.push(c);
+            let keyword = JS_KEYWORDS.contains(&self.js_word.as_str());
+            return Js::Code { regex: keyword };
+        }
+        self.js_word.clear();
+
+        match c {
+            '/' => Js::Slash { regex },
+            '"' | '\'' | '`' => Js::String {
+                quote: c,
+                escaped: false,
+            },
+            '{' => {
+                if let Some(open) = self.js_substitutions.last_mut() {
+                    *open += 1;
+                }
+                Js::Code { regex: true }
+            }
+            '}' => match self.js_substitutions.last_mut() {
+                // The end of a `${...}`, back into the template literal
+                Some(0) => {
+                    self.js_substitutions.pop();
+                    Js::String {
+                        quote: '`',
+                        escaped: false,
+                    }
+                }
+                Some(open) => {
+                    *open -= 1;
+                    Js::Code { regex: true }
+                }
+                None => Js::Code { regex: true },
+            },
+            ')' | ']' => Js::Code { regex: false },
+            _ => Js::Code { regex: true },
+        }
+    }
+
+    // The context for a placeholder at the current position
+    fn context(&self) -> HtmlContext {
+        if self.lost || self.alternatives.iter().any(|alternative| alternative.lost) {
+            return HtmlContext::Unsafe("in or after an {#each} whose items end in another place");
+        }
+        let context = self.context_here();
+        if self
+            .alternatives
+            .iter()
+            .any(|alternative| alternative.context_here() != context)
+        {
+            return HtmlContext::Unsafe("after blocks that end in different places in the markup");
+        }
+        context
+    }
+
+    fn context_here(&self) -> HtmlContext {
+        match self.state {
+            State::Data => HtmlContext::Text,
+            State::TagName => HtmlContext::Unsafe("tag name"),
+            State::BeforeAttrName | State::AttrName | State::AfterAttrName => {
+                HtmlContext::Unsafe("attribute name")
+            }
+            State::BeforeAttrValue => self.attr_context(None, true),
+            State::AttrValue { quote, empty } => self.attr_context(quote, empty),
+            State::Comment => HtmlContext::Unsafe("HTML comment"),
+            State::RawText { script: true } => match self.js {
+                Js::String { quote: '`', .. } => HtmlContext::ScriptTemplate,
+                Js::String { .. } => HtmlContext::Script,
+                Js::LineComment | Js::BlockComment { .. } => {
+                    HtmlContext::Unsafe("JavaScript comment")
+                }
+                Js::Regex { .. } | Js::Slash { regex: true } => {
+                    HtmlContext::Unsafe("JavaScript regular expression")
+                }
+                Js::Code { .. } | Js::Slash { regex: false } => {
+                    HtmlContext::Unsafe("script outside of a string literal")
+                }
+            },
+            State::RawText { script: false } => HtmlContext::Style,
+        }
+    }
+
+    fn attr_context(&self, quote: Option<char>, empty: bool) -> HtmlContext {
+        if self.attr.starts_with("on") {
+            HtmlContext::Unsafe("event handler attribute")
+        } else if self.attr == "srcdoc" {
+            HtmlContext::Unsafe("srcdoc attribute")
+        } else if self.attr == "style" {
+            HtmlContext::Style
+        } else if URL_ATTRIBUTES.contains(&self.attr.as_str()) {
+            HtmlContext::Url {
+                start: empty,
+                quoted: quote.is_some(),
+            }
+        } else if quote.is_some() {
+            HtmlContext::Attribute
+        } else {
+            HtmlContext::UnquotedAttribute
+        }
+    }
+
+    // Whether text written after this and after `other` would be in the same place in the markup
+    fn same_place(&self, other: &Scanner) -> bool {
+        let same_tag = self.tag == other.tag && self.closing == other.closing;
+        self.state == other.state
+            && self.lost == other.lost
+            && match self.state {
+                State::Data | State::Comment | State::RawText { script: false } => true,
+                State::TagName | State::BeforeAttrName => same_tag,
+                State::AttrName
+                | State::AfterAttrName
+                | State::BeforeAttrValue
+                | State::AttrValue { .. } => same_tag && self.attr == other.attr,
+                State::RawText { script: true } => {
+                    self.js == other.js && self.js_substitutions == other.js_substitutions
+                }
+            }
+    }
+
+    // Carries on from where either this or `other` left off
+    fn join(&mut self, mut other: Scanner) {
+        let alternatives = std::mem::take(&mut other.alternatives);
+        for other in std::iter::once(other).chain(alternatives) {
+            if !self.same_place(&other)
+                && !self
+                    .alternatives
+                    .iter()
+                    .any(|known| known.same_place(&other))
+            {
+                self.alternatives.push(other);
+            }
+        }
+    }
+
+    // Whether every place `other` could be is one this could be as well
+    fn covers(&self, other: &Scanner) -> bool {
+        std::iter::once(other)
+            .chain(&other.alternatives)
+            .all(|other| {
+                std::iter::once(self)
+                    .chain(&self.alternatives)
+                    .any(|known| known.same_place(other))
+            })
+    }
+
+    // Drops the alternatives that have ended up in the same place as this
+    fn converge(&mut self) {
+        if self.alternatives.is_empty() {
+            return;
+        }
+        let mut alternatives = std::mem::take(&mut self.alternatives);
+        alternatives.retain(|alternative| !self.same_place(alternative));
+        self.alternatives = alternatives;
+    }
+
+    // Moves past text inserted at the current position
+    fn inserted(&mut self) {
+        for alternative in &mut self.alternatives {
+            alternative.skip_insertion();
+        }
+        self.skip_insertion();
+        self.converge();
+    }
+
+    fn skip_insertion(&mut self) {
+        self.state = match self.state {
+            State::BeforeAttrValue => State::AttrValue {
+                quote: None,
+                empty: false,
+            },
+            State::AttrValue { quote, .. } => State::AttrValue {
+                quote,
+                empty: false,
+            },
+            state => state,
+        };
+    }
+}
+
+#[cfg(test)]
+mod html_context_tests {
+    use super::*;
+    use crate::parser::{parse_nodes, Whitespace};
+    use crate::Syntax;
+
+    fn placeholder_contexts(body: &str) -> Vec<HtmlContext> {
+        let nodes = parse_nodes(body, &Syntax::default(), Whitespace::default()).unwrap();
+        contexts(body, &nodes)
+            .placeholders
+            .into_iter()
+            .map(|(_, context)| context)
+            .collect()
+    }
+
+    #[test]
+    fn test_text_and_attributes() {
+        assert_eq!(
+            placeholder_contexts("<p class=\"{a} {b}\" title={c} data-x='{d}'>{e}</p>{f}"),
+            vec![
+                HtmlContext::Attribute,
+                HtmlContext::Attribute,
+                HtmlContext::UnquotedAttribute,
+                HtmlContext::Attribute,
+                HtmlContext::Text,
+                HtmlContext::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_urls() {
+        assert_eq!(
+            placeholder_contexts("<a href=\"{a}?q={b}\"><img src={c}>"),
+            vec![
+                HtmlContext::Url {
+                    start: true,
+                    quoted: true
+                },
+                HtmlContext::Url {
+                    start: false,
+                    quoted: true
+                },
+                HtmlContext::Url {
+                    start: true,
+                    quoted: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_and_style() {
+        assert_eq!(
+            placeholder_contexts(
+                "<script>var a = \"{a}\\\"\"; {b}</script><style>p {{ color: {c}; }}</style><p>{d}</p>"
+            ),
+            vec![
+                HtmlContext::Script,
+                HtmlContext::Unsafe("script outside of a string literal"),
+                HtmlContext::Style,
+                HtmlContext::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_comments_and_regexes() {
+        assert_eq!(
+            placeholder_contexts(concat!(
+                "<script>// don't\nvar x = {a}; /* it's {b} */ var r = /'/; f({c});",
+                " var y = a / 2 + \"{d}\"; return /[/'\\/]/.test('{e}');</script>",
+            )),
+            vec![
+                HtmlContext::Unsafe("script outside of a string literal"),
+                HtmlContext::Unsafe("JavaScript comment"),
+                HtmlContext::Unsafe("script outside of a string literal"),
+                HtmlContext::Script,
+                HtmlContext::Script,
+            ]
+        );
+        assert_eq!(
+            placeholder_contexts("<script>var r = /{a}/;</script>"),
+            vec![HtmlContext::Unsafe("JavaScript regular expression")]
+        );
+    }
+
+    #[test]
+    fn test_template_literals() {
+        assert_eq!(
+            placeholder_contexts("<script>`{a} ${{ {{ x: '}}' }} }} {b}`; {c}</script>"),
+            vec![
+                HtmlContext::ScriptTemplate,
+                HtmlContext::ScriptTemplate,
+                HtmlContext::Unsafe("script outside of a string literal"),
+            ]
+        );
+
+        let mut out = String::new();
+        HtmlContext::ScriptTemplate
+            .escape_into("${alert(1)}`", &mut out)
+            .unwrap();
+        assert_eq!(out, "\\u0024{alert(1)}\\u0060");
+    }
+
+    #[test]
+    fn test_url_like_attributes() {
+        assert_eq!(
+            placeholder_contexts(
+                "<svg><a xlink:href=\"{a}\"></a></svg><object data={b}><img srcset=\"{c}\"><iframe srcdoc=\"{d}\">"
+            ),
+            vec![
+                HtmlContext::Url {
+                    start: true,
+                    quoted: true
+                },
+                HtmlContext::Url {
+                    start: true,
+                    quoted: false
+                },
+                HtmlContext::Url {
+                    start: true,
+                    quoted: true
+                },
+                HtmlContext::Unsafe("srcdoc attribute"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsafe_places() {
+        assert_eq!(
+            placeholder_contexts("<{a}><p {b}><!-- {c} --><b onclick=\"{d}\">{e}"),
+            vec![
+                HtmlContext::Unsafe("tag name"),
+                HtmlContext::Unsafe("attribute name"),
+                HtmlContext::Unsafe("HTML comment"),
+                HtmlContext::Unsafe("event handler attribute"),
+                HtmlContext::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_less_than_in_text() {
+        assert_eq!(placeholder_contexts("1 < 2 {a}"), vec![HtmlContext::Text]);
+    }
+
+    #[test]
+    fn test_escaping() {
+        let mut out = String::new();
+        HtmlContext::UnquotedAttribute
+            .escape_into("a b=c", &mut out)
+            .unwrap();
+        assert_eq!(out, "a&#x20;b&#x3D;c");
+
+        let url = HtmlContext::Url {
+            start: true,
+            quoted: true,
+        };
+        assert!(url.escape_into("javascript:alert(1)", &mut out).is_err());
+        assert!(url.escape_into("/relative?a=b:c", &mut out).is_ok());
+        assert!(url.escape_into("HTTPS://example.com", &mut out).is_ok());
+    }
+
+    #[test]
+    fn test_if_branches() {
+        assert_eq!(
+            placeholder_contexts("<p {#if a}class=\"{b}\"{:else}id={c}{/if}>{d}"),
+            vec![
+                HtmlContext::Attribute,
+                HtmlContext::UnquotedAttribute,
+                HtmlContext::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_branches_ending_apart() {
+        assert_eq!(
+            placeholder_contexts(
+                "{#if a}<p>{:else}<script>{/if}{b}</script>{c}<a {#if d}href{:else}title{/if}=\"{e}\">"
+            ),
+            vec![
+                HtmlContext::Unsafe("after blocks that end in different places in the markup"),
+                HtmlContext::Text,
+                HtmlContext::Unsafe("after blocks that end in different places in the markup"),
+            ]
+        );
+        // Branches that end in different states but give the same context are fine
+        assert_eq!(
+            placeholder_contexts("<b title=\"{#if a}x{/if}{b}\">"),
+            vec![HtmlContext::Attribute]
+        );
+    }
+
+    #[test]
+    fn test_each_items_ending_apart() {
+        let apart = HtmlContext::Unsafe("after blocks that end in different places in the markup");
+        assert_eq!(
+            placeholder_contexts("{#each xs as x}{a}<script>{/each}{b}"),
+            vec![apart, apart]
+        );
+        // Every item opens another `${`, so there is no telling where the items start
+        let lost = HtmlContext::Unsafe("in or after an {#each} whose items end in another place");
+        assert_eq!(
+            placeholder_contexts("<script>{#each xs as x}'{a}' + `${{{/each}'{b}'</script>"),
+            vec![lost, lost]
+        );
+        assert_eq!(
+            placeholder_contexts(
+                "{#each xs as x}<li>{a}</li>{:empty}<script>{/each}{b}</script>{c}"
+            ),
+            vec![HtmlContext::Text, apart, HtmlContext::Text]
+        );
+        // Items that end where they started can follow each other
+        assert_eq!(
+            placeholder_contexts("<p class=\"{#each xs as x}{a} {/each}\">{b}"),
+            vec![HtmlContext::Attribute, HtmlContext::Text]
+        );
+    }
+}
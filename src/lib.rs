@@ -1,167 +1,431 @@
-use std::{collections::HashMap, fmt::Formatter, result};
+use std::collections::HashMap;
 
-#[derive(Debug)]
-pub enum ParseError {
-    MissingOpenBrace(usize),
-    MissingCloseBrace(usize),
-    MissingTemplate(String),
-}
+mod compiled;
+mod context;
+mod error;
+mod escape;
+mod filter;
+mod html;
+mod loader;
+mod parser;
+mod registry;
+mod syntax;
+mod value;
+mod watch;
 
-impl std::error::Error for ParseError {}
-
-impl std::fmt::Display for ParseError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::MissingTemplate(name) => write!(
-                f,
-                "sub_templates does not have any template indexed under: {}",
-                name
-            ),
-            Self::MissingCloseBrace(loc) => write!(
-                f,
-                "Open brace at {} does not have a corresponding close brace",
-                loc
-            ),
-            Self::MissingOpenBrace(loc) => write!(
-                f,
-                "Close brace at {} does not have a corresponding open brace",
-                loc
-            ),
-        }
-    }
-}
+pub use compiled::CompiledTemplate;
+use compiled::{Binding, State};
+pub use context::{Context, Missing, MissingCallback};
+pub use error::{Location, ParseError};
+pub use escape::Escape;
+pub use filter::FilterFn;
+use filter::{FilterScope, Filters};
+use html::{HtmlContext, Scan};
+pub use loader::TemplateLoader;
+use parser::{offset_in, parse_nodes, scan, to_nodes, TokenKind, Whitespace};
+pub use parser::{Arg, Filter, Node};
+pub use registry::Registry;
+pub use syntax::Syntax;
+pub use value::Value;
 
+#[derive(Debug)]
 pub struct NestedTemplate {
+    name: Option<String>,
     body: String,
     sub_templates: HashMap<String, NestedTemplate>,
+    values: HashMap<String, Value>,
+    escape: Option<Escape>,
+    safe: bool,
+    filters: Filters,
+    syntax: Syntax,
+    whitespace: Whitespace,
+    auto_indent: Option<bool>,
 }
 
-fn render_helper(body: &str) -> Result<Vec<(bool, String)>, ParseError> {
-    // Handle any escaped opening braces
-    if let Some(start_escape) = body.find("{{") {
-        let pre_escape = &body[..start_escape];
-
-        // This checks if there is anything following the escaped brace
-        let mut post_escape = "";
-        if start_escape + 2 < body.len() {
-            post_escape = &body[start_escape + 2..];
+impl NestedTemplate {
+    pub fn new(body: &str) -> NestedTemplate {
+        NestedTemplate {
+            name: None,
+            body: body.to_string(),
+            sub_templates: HashMap::new(),
+            values: HashMap::new(),
+            escape: None,
+            safe: false,
+            filters: Filters::default(),
+            syntax: Syntax::default(),
+            whitespace: Whitespace::default(),
+            auto_indent: None,
         }
+    }
 
-        // Render everything before the escaped brace
-        let mut pre_vec = render_helper(pre_escape)?;
-        pre_vec.push((false, "{".to_string()));
+    /// Sets the name or path that error locations in this template are reported under.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
 
-        // Render everything after the escaped brace
-        let mut post_vec = render_helper(post_escape)?;
-        pre_vec.append(&mut post_vec);
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
 
-        return Ok(pre_vec);
+    /// Registers `template` under `name`. A template without a name of its own is named after the
+    /// placeholder it is registered under.
+    pub fn add_sub_template(&mut self, name: &str, mut template: NestedTemplate) {
+        template.name.get_or_insert_with(|| name.to_string());
+        self.values.remove(name);
+        self.sub_templates.insert(name.to_string(), template);
     }
 
-    // Handle any escaped closing braces
-    if let Some(start_escape) = body.find("}}") {
-        let pre_escape = &body[..start_escape];
+    /// Binds `name` to a value. Unlike a sub-template the value is inserted exactly as given, so
+    /// strings may contain braces. Replaces any sub-template registered under the same name.
+    pub fn set_value(&mut self, name: &str, value: impl Into<Value>) {
+        self.sub_templates.remove(name);
+        self.values.insert(name.to_string(), value.into());
+    }
 
-        // This checks if there is anything following the escaped brace
-        let mut post_escape = "";
-        if start_escape + 2 < body.len() {
-            post_escape = &body[start_escape + 2..];
-        }
+    /// Sets how values and sub-templates inserted into this template are escaped.
+    ///
+    /// Without an explicit mode, templates whose name ends in `.html` or `.htm` escape HTML and
+    /// every other template uses the mode of the template it is inserted into. Top level templates
+    /// default to [`Escape::None`].
+    ///
+    /// In [`Escape::Html`] mode each placeholder is escaped for where it sits in the markup: text,
+    /// quoted or unquoted attribute values, URL attributes, string literals in `<script>` or
+    /// `<style>` content. Placeholders that cannot be escaped safely where they are, such as in a
+    /// tag name, a `srcdoc` attribute, or in script outside of a string, fail with
+    /// [`ParseError::Unsafe`].
+    ///
+    /// A sub-template in the same mode as the template it is inserted into has already escaped
+    /// its values, so its output goes in as markup. In a URL, script or style it is rendered
+    /// without escaping and escaped as a whole instead. A sub-template in a different mode is
+    /// escaped as a whole as well.
+    pub fn set_escape(&mut self, escape: Escape) {
+        self.escape = Some(escape);
+    }
 
-        // Render everything before the escaped brace
-        let mut pre_vec = render_helper(pre_escape)?;
-        pre_vec.push((false, "}".to_string()));
+    /// Marks this template as trusted markup, so its rendered output is inserted into its parent
+    /// without being escaped. Values inside it are still escaped according to its own mode.
+    pub fn set_safe(&mut self, safe: bool) {
+        self.safe = safe;
+    }
 
-        let mut post_vec = render_helper(post_escape)?;
-        pre_vec.append(&mut post_vec);
+    /// Sets the delimiters that tags in the body of this template are written with. Other
+    /// templates, including the sub-templates of this one, keep their own.
+    pub fn set_syntax(&mut self, syntax: Syntax) {
+        self.syntax = syntax;
+    }
+
+    /// Drops the first newline after each block tag, like `{#if}`, `{/each}` or a comment, so a
+    /// tag on a line of its own does not leave an empty line behind. Off by default.
+    ///
+    /// Independently of this, any tag can drop the whitespace before or after it with a `-`
+    /// next to the delimiter, as in `{- name -}`.
+    pub fn set_trim_blocks(&mut self, trim_blocks: bool) {
+        self.whitespace.trim_blocks = trim_blocks;
+    }
+
+    /// Drops the spaces and tabs before each block tag that starts a line. Off by default.
+    pub fn set_lstrip_blocks(&mut self, lstrip_blocks: bool) {
+        self.whitespace.lstrip_blocks = lstrip_blocks;
+    }
+
+    /// Indents every line after the first of multi-line text inserted by a placeholder, such as
+    /// the output of a sub-template, to the column the placeholder sits at. Blank lines are left
+    /// empty. Templates without an explicit setting use the setting of the template they are
+    /// inserted into, and top level templates default to `false`.
+    pub fn set_auto_indent(&mut self, auto_indent: bool) {
+        self.auto_indent = Some(auto_indent);
+    }
 
-        return Ok(pre_vec);
+    /// Registers a filter for `{name | filter}` placeholders in this template and every template
+    /// inserted into it. Replaces a built-in filter of the same name, other than `default`,
+    /// `escape` and `safe`.
+    ///
+    /// The built-in filters are `upper`, `lower`, `trim`, `truncate(length)`,
+    /// `truncate(length, "end")`, `urlencode`, `json`, `date` and `date("format")`. `escape`
+    /// escapes for where the placeholder sits, HTML if the template does not escape, and marks the
+    /// result safe like `safe` does, so later filters and insertion leave it as it is.
+    pub fn register_filter(
+        &mut self,
+        name: &str,
+        filter: impl Fn(&str, &[Arg]) -> Result<String, String> + Send + Sync + 'static,
+    ) {
+        self.filters.insert(name, Box::new(filter));
     }
 
-    let start_template = body.find("{");
-    let end_template = body.find("}");
+    // The escape mode set on or implied by this template, if any
+    fn escape_mode(&self) -> Option<Escape> {
+        self.escape.or_else(|| {
+            let name = self.name.as_deref()?;
+            (name.ends_with(".html") || name.ends_with(".htm")).then_some(Escape::Html)
+        })
+    }
 
-    if start_template.is_none() && end_template.is_none() {
-        // There are no template strings left, so return the whole string
+    fn parse(&self) -> Result<Vec<Node<'_>>, ParseError> {
+        parse_nodes(&self.body, &self.syntax, self.whitespace)
+            .map_err(|err| err.in_template(self.name()))
+    }
 
-        return Ok(vec![(false, body.to_string())]);
-    } else if start_template.is_some() && end_template.is_none() {
-        // The template is never closed, so return a missing close brace error
+    /// Parses this template and all of its sub-templates so the result can be rendered repeatedly.
+    pub fn compile(&self) -> Result<CompiledTemplate<'_>, ParseError> {
+        let mut sub_templates = HashMap::with_capacity(self.sub_templates.len());
+        for (name, template) in self.sub_templates.iter() {
+            sub_templates.insert(name.as_str(), template.compile()?);
+        }
 
-        return Err(ParseError::MissingCloseBrace(start_template.unwrap()));
-    } else if start_template.is_none() && end_template.is_some() {
-        // The template is never opened, so return a missing open brace error
+        Ok(CompiledTemplate::new(self, self.parse()?, sub_templates))
+    }
 
-        return Err(ParseError::MissingOpenBrace(end_template.unwrap()));
+    /// Checks this template and every sub-template under it without rendering anything, returning
+    /// all of the problems found rather than stopping at the first one. An empty `Vec` means the
+    /// template tree can be rendered.
+    pub fn validate(&self) -> Vec<ParseError> {
+        self.validate_with(&Context::new())
     }
 
-    // Check to make sure that opening brace comes before the closing brace. Otherwise, treat it as
-    // a missing open brace error
-    if start_template.unwrap() > end_template.unwrap() {
-        return Err(ParseError::MissingOpenBrace(end_template.unwrap()));
+    /// Like [`validate`](Self::validate), but placeholders that `ctx` provides are not reported as
+    /// missing.
+    pub fn validate_with(&self, ctx: &Context) -> Vec<ParseError> {
+        self.validate_in(State::new(ctx))
     }
 
-    let pre_template = &body[..start_template.unwrap()]; // String preceding template start
-    let post_template = &body[end_template.unwrap() + 1..]; // String proceding end of template
-    let mut post_vec = render_helper(post_template)?; // Render everything after template
-    let template_name = &body[start_template.unwrap() + 1..end_template.unwrap()].trim();
+    pub(crate) fn validate_in(&self, state: State) -> Vec<ParseError> {
+        let mut errors = Vec::new();
+        self.validate_into(&mut errors, state, Escape::None, &[], None);
+        errors
+    }
 
-    // Everything before the template has already been rendered, so just return the string. The
-    let mut result = vec![
-        (false, pre_template.to_string()),
-        (true, template_name.to_string()),
-    ];
-    result.append(&mut post_vec);
+    // `loop_names` are the names bound by `{#each}` blocks in the templates above this one, which
+    // may be in scope when this one is rendered, and `filters` the filters they registered
+    fn validate_into(
+        &self,
+        errors: &mut Vec<ParseError>,
+        state: State,
+        inherited: Escape,
+        loop_names: &[&str],
+        filters: Option<&FilterScope>,
+    ) {
+        let (tokens, mut diagnostics) = scan(&self.body, &self.syntax, self.whitespace);
+        for token in tokens.iter() {
+            if token.kind == TokenKind::Placeholder && token.text.is_empty() {
+                let loc = Location::new(&self.body, token.offset);
+                diagnostics.push(ParseError::EmptyPlaceholder(loc));
+            }
+        }
 
-    Ok(result)
-}
+        let nodes = to_nodes(&self.body, tokens, &mut diagnostics);
+        let scope = CompiledTemplate::new(self, nodes, HashMap::new());
+        let filters = FilterScope {
+            filters: &self.filters,
+            parent: filters,
+        };
+        let escape = self.escape_mode().unwrap_or(inherited);
 
-impl NestedTemplate {
-    pub fn new(body: &str) -> NestedTemplate {
-        NestedTemplate {
-            body: body.to_string(),
-            sub_templates: HashMap::new(),
+        // Blocks that override the blocks of an HTML base are checked where they end up in it
+        let built;
+        let base = match scope.nodes().iter().find_map(|node| match node {
+            Node::Extends(base) => scope.resolve(base, state).ok(),
+            _ => None,
+        }) {
+            Some(Binding::Template(base)) => match base.parse() {
+                Ok(nodes) => {
+                    built = CompiledTemplate::new(base, nodes, HashMap::new());
+                    Some(&built)
+                }
+                Err(_) => None,
+            },
+            Some(Binding::Compiled(base)) => Some(base),
+            Some(Binding::Parsed(base, parsed)) => {
+                built = parsed.compile(base);
+                Some(&built)
+            }
+            _ => None,
         }
-    }
+        .filter(|base| base.template().escape_mode().unwrap_or(escape) == Escape::Html);
 
-    pub fn add_sub_template(&mut self, name: &str, template: NestedTemplate) {
-        self.sub_templates.insert(name.to_string(), template);
+        let mut check = Check {
+            state,
+            filters: &filters,
+            escape,
+            loop_names: loop_names.to_vec(),
+            base,
+            diagnostics,
+        };
+        self.validate_nodes(&scope, scope.nodes(), &mut check, None);
+
+        errors.extend(
+            check
+                .diagnostics
+                .into_iter()
+                .map(|err| err.in_template(self.name())),
+        );
+
+        // Sort so the diagnostics come out in the same order on every run
+        let mut names: Vec<&String> = self.sub_templates.keys().collect();
+        names.sort();
+        for name in names {
+            self.sub_templates[name].validate_into(
+                errors,
+                state,
+                check.escape,
+                &check.loop_names,
+                Some(check.filters),
+            );
+        }
     }
 
-    pub fn render(&self) -> Result<String, ParseError> {
-        let pairs = render_helper(&self.body)?;
-        let mut rendered_template = String::new();
+    // `html` is where the nodes sit in the markup when they are in a block that overrides one in
+    // the base template
+    fn validate_nodes<'n>(
+        &self,
+        scope: &CompiledTemplate,
+        nodes: &'n [Node],
+        check: &mut Check<'_, 'n>,
+        html: Option<&Scan>,
+    ) {
+        for node in nodes {
+            match node {
+                Node::Literal(_) => (),
+                // Empty names were already reported
+                Node::Placeholder { name: "", .. } => (),
+                Node::Placeholder { name, filters } => {
+                    for filter in filters {
+                        if !FilterScope::is_known(Some(check.filters), filter.name) {
+                            let err = ParseError::UnknownFilter(filter.name.to_string());
+                            check.diagnostics.push(err);
+                        }
+                    }
 
-        for (is_template, value) in pairs.iter() {
-            if *is_template {
-                let sub_template = match self.sub_templates.get(value) {
-                    Some(t) => t,
-                    None => return Err(ParseError::MissingTemplate(value.to_string())),
-                };
+                    let default = filters.iter().any(|filter| filter.name == "default");
+                    let trusted = if check.is_loop_bound(name) {
+                        false
+                    } else {
+                        match scope.resolve(name, check.state) {
+                            Ok(binding) => binding.is_trusted(),
+                            Err(ParseError::MissingTemplate(_))
+                                if default
+                                    || !matches!(check.state.ctx.missing(), Missing::Error) =>
+                            {
+                                continue
+                            }
+                            Err(err) => {
+                                check.diagnostics.push(err);
+                                continue;
+                            }
+                        }
+                    };
 
-                rendered_template.push_str(&sub_template.render()?);
-            } else {
-                rendered_template.push_str(value);
+                    if check.escape != Escape::Html || trusted {
+                        continue;
+                    }
+                    if let HtmlContext::Unsafe(reason) = scope.html_context_in(name, html) {
+                        let loc = Location::new(&self.body, offset_in(&self.body, name));
+                        check.diagnostics.push(ParseError::Unsafe(loc, reason));
+                    }
+                }
+                // A missing condition is just false, so only the branches are checked
+                Node::If {
+                    then, otherwise, ..
+                } => {
+                    self.validate_nodes(scope, then, check, html);
+                    self.validate_nodes(scope, otherwise, check, html);
+                }
+                Node::Each {
+                    list,
+                    item,
+                    body,
+                    empty,
+                } => {
+                    if !check.is_loop_bound(list) {
+                        if let Err(err) = scope.resolve(list, check.state) {
+                            check.diagnostics.push(err);
+                        }
+                    }
+                    check.loop_names.extend([*item, "loop"]);
+                    self.validate_nodes(scope, body, check, html);
+                    self.validate_nodes(scope, empty, check, html);
+                }
+                Node::Extends(base) => {
+                    if let Err(err) = scope.resolve(base, check.state) {
+                        check.diagnostics.push(err);
+                    }
+                }
+                Node::Block { name, body } => {
+                    let placed = check
+                        .base
+                        .and_then(|base| base.html_scan().block_named(name, &self.body, body));
+                    match placed {
+                        Some(scan) => {
+                            let escape = std::mem::replace(&mut check.escape, Escape::Html);
+                            self.validate_nodes(scope, body, check, Some(&scan));
+                            check.escape = escape;
+                        }
+                        None => self.validate_nodes(scope, body, check, html),
+                    }
+                }
+                Node::Super => (),
             }
         }
+    }
 
+    pub fn render(&self) -> Result<String, ParseError> {
+        self.render_with(&Context::new())
+    }
+
+    /// Renders the template, looking placeholders up in `ctx` before falling back to the values
+    /// and sub-templates registered on the template. The context is passed down to every
+    /// sub-template as well.
+    pub fn render_with(&self, ctx: &Context) -> Result<String, ParseError> {
+        self.render_in(State::new(ctx))
+    }
+
+    pub(crate) fn render_in(&self, state: State) -> Result<String, ParseError> {
+        let mut rendered_template = String::new();
+        self.render_into(&mut rendered_template, state)?;
         Ok(rendered_template)
     }
+
+    // Unlike compile(), only the sub-templates that are actually referenced get parsed
+    fn render_into(&self, out: &mut String, parent: State) -> Result<(), ParseError> {
+        CompiledTemplate::new(self, self.parse()?, HashMap::new()).render_into(out, parent)
+    }
+}
+
+// What validate_into hands down while walking the nodes of one template
+struct Check<'c, 'n> {
+    state: State<'c>,
+    filters: &'c FilterScope<'c>,
+    escape: Escape,
+    loop_names: Vec<&'n str>,
+    /// The template this one extends, if it is rendered as HTML
+    base: Option<&'n CompiledTemplate<'n>>,
+    diagnostics: Vec<ParseError>,
+}
+
+impl Check<'_, '_> {
+    // Loop variables only exist while rendering, so paths starting with one can't be checked
+    fn is_loop_bound(&self, name: &str) -> bool {
+        let first = name.split('.').next().unwrap_or_default();
+        self.loop_names.contains(&first)
+    }
 }
 
 #[cfg(test)]
-mod NestedTemplate_tests {
+mod nested_template_tests {
     use super::*;
 
     #[test]
     fn test_successful_render() {
         let mut parent = NestedTemplate::new("<!DOCTYPE html><body>{first_child}</body>");
-        let mut first_child = NestedTemplate::new("<div>This is a test</div><script>{second_child}</script>");
+        let mut first_child =
+            NestedTemplate::new("<div>This is a test</div><script>{second_child}</script>");
         let second_child = NestedTemplate::new("second_child");
 
         first_child.add_sub_template("second_child", second_child);
         parent.add_sub_template("first_child", first_child);
-        assert_eq!(parent.render().unwrap(), "<!DOCTYPE html><body><div>This is a test</div><script>second_child</script></body>");
+        assert_eq!(
+            parent.render().unwrap(),
+            "<!DOCTYPE html><body><div>This is a test</div><script>second_child</script></body>"
+        );
     }
 
     #[test]
@@ -178,92 +442,1191 @@ mod NestedTemplate_tests {
 }
 
 #[cfg(test)]
-mod render_helper_tests {
+mod value_tests {
     use super::*;
 
     #[test]
-    fn test_render_helper_missing_open() {
-        match render_helper("something }") {
-            Ok(_) => panic!("render_helper did not catch missing \"{{\""),
-            Err(ParseError::MissingOpenBrace(_)) => (),
-            _ => panic!("render_helper caught wrong error"),
+    fn test_value_is_not_parsed() {
+        let mut template = NestedTemplate::new("<p>Hello {user}</p>");
+        template.set_value("user", "Ann {x}}");
+        assert_eq!(template.render().unwrap(), "<p>Hello Ann {x}}</p>");
+        assert_eq!(
+            template.compile().unwrap().render().unwrap(),
+            "<p>Hello Ann {x}}</p>"
+        );
+        assert!(template.validate().is_empty());
+    }
+
+    #[test]
+    fn test_value_and_sub_template_replace_each_other() {
+        let mut template = NestedTemplate::new("{slot}");
+        template.set_value("slot", "value");
+        template.add_sub_template("slot", NestedTemplate::new("template"));
+        assert_eq!(template.render().unwrap(), "template");
+
+        template.set_value("slot", "value");
+        assert_eq!(template.render().unwrap(), "value");
+    }
+}
+
+#[cfg(test)]
+mod context_tests {
+    use super::*;
+
+    fn layout() -> NestedTemplate {
+        let mut layout = NestedTemplate::new("<title>{title}</title><body>{body}</body>");
+        layout.set_value("title", "Default title");
+        layout.add_sub_template("body", NestedTemplate::new("<p>Hi {user}</p>"));
+        layout
+    }
+
+    #[test]
+    fn test_context_reaches_sub_templates() {
+        let layout = layout();
+        let mut ann = Context::new();
+        ann.set_value("user", "Ann");
+        let mut bob = Context::new();
+        bob.set_value("user", "Bob");
+        bob.set_value("title", "Bob's page");
+
+        assert_eq!(
+            layout.render_with(&ann).unwrap(),
+            "<title>Default title</title><body><p>Hi Ann</p></body>"
+        );
+        assert_eq!(
+            layout.compile().unwrap().render_with(&bob).unwrap(),
+            "<title>Bob's page</title><body><p>Hi Bob</p></body>"
+        );
+        assert!(layout.render().is_err());
+    }
+
+    #[test]
+    fn test_context_sub_template_overrides_static() {
+        let layout = layout();
+        let mut ctx = Context::new();
+        ctx.add_sub_template("body", NestedTemplate::new("<h1>{heading}</h1>"));
+        ctx.set_value("heading", "Welcome");
+        assert_eq!(
+            layout.render_with(&ctx).unwrap(),
+            "<title>Default title</title><body><h1>Welcome</h1></body>"
+        );
+    }
+
+    #[test]
+    fn test_validate_with_context() {
+        let layout = layout();
+        assert_eq!(layout.validate().len(), 1);
+
+        let mut ctx = Context::new();
+        ctx.set_value("user", "Ann");
+        assert!(layout.validate_with(&ctx).is_empty());
+    }
+
+    #[test]
+    fn test_template_shared_between_threads() {
+        let layout = layout();
+        std::thread::scope(|scope| {
+            for user in ["Ann", "Bob"] {
+                let layout = &layout;
+                scope.spawn(move || {
+                    let mut ctx = Context::new();
+                    ctx.set_value("user", user);
+                    let rendered = layout.render_with(&ctx).unwrap();
+                    assert!(rendered.contains(&format!("Hi {}", user)));
+                });
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod structured_value_tests {
+    use super::*;
+
+    #[test]
+    fn test_scalar_values_render_as_text() {
+        let mut template = NestedTemplate::new("{name} is {age} ({admin}, {ratio})");
+        let mut ctx = Context::new();
+        ctx.set_value("name", String::from("Ann"));
+        ctx.set_value("age", 42);
+        template.set_value("admin", false);
+        template.set_value("ratio", 0.5);
+        assert_eq!(
+            template.render_with(&ctx).unwrap(),
+            "Ann is 42 (false, 0.5)"
+        );
+    }
+
+    #[test]
+    fn test_structured_value_is_not_text() {
+        let mut template = NestedTemplate::new("{user}");
+        let user: Value = [("name", "Ann")].into_iter().collect();
+        template.set_value("user", user);
+
+        match template.render() {
+            Err(ParseError::NotText(name, kind)) => {
+                assert_eq!((name.as_str(), kind), ("user", "map"))
+            }
+            other => panic!("unexpected result {:?}", other),
         }
+
+        template.set_value("user", Option::<&str>::None);
+        assert!(matches!(
+            template.render(),
+            Err(ParseError::NotText(_, "null"))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod dotted_path_tests {
+    use super::*;
+
+    fn layout() -> NestedTemplate {
+        let mut header = NestedTemplate::new("<h1>{title}</h1>");
+        header.set_value("title", "Home");
+        let mut layout = NestedTemplate::new("");
+        layout.add_sub_template("header", header);
+        layout
+    }
+
+    #[test]
+    fn test_path_through_sub_templates() {
+        let mut page = NestedTemplate::new("{layout.header.title}|{layout.header}");
+        page.add_sub_template("layout", layout());
+        assert_eq!(page.render().unwrap(), "Home|<h1>Home</h1>");
+        assert_eq!(
+            page.compile().unwrap().render().unwrap(),
+            "Home|<h1>Home</h1>"
+        );
+    }
+
+    #[test]
+    fn test_path_through_context_values() {
+        let template = NestedTemplate::new("{user.address.city}, {user.tags.1}");
+        let address: Value = [("city", "Oslo")].into_iter().collect();
+        let user: Value = [("address", address), ("tags", Value::from(vec!["a", "b"]))]
+            .into_iter()
+            .collect();
+        let mut ctx = Context::new();
+        ctx.set_value("user", user);
+        assert_eq!(template.render_with(&ctx).unwrap(), "Oslo, b");
     }
 
     #[test]
-    fn test_render_helper_missing_close() {
-        match render_helper("something {") {
-            Ok(_) => panic!("render_helper did not catch missing \"}}\""),
-            Err(ParseError::MissingCloseBrace(_)) => (),
-            _ => panic!("render_helper caught wrong error"),
+    fn test_missing_segment_is_reported() {
+        let mut page = NestedTemplate::new("{layout.footer.title}");
+        page.add_sub_template("layout", layout());
+        match page.render() {
+            Err(ParseError::MissingTemplate(path)) => assert_eq!(path, "layout.footer"),
+            other => panic!("unexpected result {:?}", other),
         }
+
+        let errors = NestedTemplate::new("{nothing.here}").validate();
+        assert!(matches!(&errors[..], [ParseError::MissingTemplate(path)] if path == "nothing"));
     }
 
     #[test]
-    fn test_render_helper_success() {
-        let val = render_helper("This is a {successful} test of the {helper_function}").unwrap();
+    fn test_whole_name_wins_over_path() {
+        let mut template = NestedTemplate::new("{a.b}");
+        template.set_value("a.b", "flat");
+        assert_eq!(template.render().unwrap(), "flat");
+    }
+}
+
+#[cfg(test)]
+mod escape_mode_tests {
+    use super::*;
+
+    fn page() -> NestedTemplate {
+        let mut page = NestedTemplate::new("<p>{user}</p>{comment}");
+        page.set_name("page.html");
+        page.add_sub_template("comment", NestedTemplate::new("<i>{text}</i>"));
+        page
+    }
+
+    #[test]
+    fn test_html_templates_escape_by_default() {
+        let mut ctx = Context::new();
+        ctx.set_value("user", "<script>alert(1)</script>");
+        ctx.set_value("text", "a & b");
         assert_eq!(
-            val,
-            vec![
-                (false, "This is a ".to_string()),
-                (true, "successful".to_string()),
-                (false, " test of the ".to_string()),
-                (true, "helper_function".to_string()),
-                (false, String::new())
-            ]
+            page().render_with(&ctx).unwrap(),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt;</p><i>a &amp; b</i>"
+        );
+
+        // Values are escaped once however deep the template that inserts them is
+        let mut page = NestedTemplate::new("<p>{quote}</p>");
+        page.set_name("page.html");
+        let mut quote = NestedTemplate::new("<q>{comment}</q>");
+        quote.add_sub_template("comment", NestedTemplate::new("<i>{text}</i>"));
+        page.add_sub_template("quote", quote);
+        ctx.set_value("text", "Tom & Jerry");
+        assert_eq!(
+            page.render_with(&ctx).unwrap(),
+            "<p><q><i>Tom &amp; Jerry</i></q></p>"
         );
     }
 
     #[test]
-    fn test_template_inside_template() {
-        match render_helper("{ {something} }") {
-            Ok(_) => panic!("render_helper allowed template inside another template"),
-            _ => (),
+    fn test_safe_markers() {
+        let mut page = page();
+        page.sub_templates
+            .get_mut("comment")
+            .unwrap()
+            .set_safe(true);
+        let mut ctx = Context::new();
+        ctx.set_value("user", Value::safe("<b>Ann</b>"));
+        ctx.set_value("text", "a & b");
+        assert_eq!(
+            page.compile().unwrap().render_with(&ctx).unwrap(),
+            "<p><b>Ann</b></p><i>a &amp; b</i>"
+        );
+    }
+
+    #[test]
+    fn test_explicit_modes() {
+        let mut link = NestedTemplate::new("/search?q={query}");
+        link.set_escape(Escape::Url);
+        link.set_value("query", "a&b c");
+        assert_eq!(link.render().unwrap(), "/search?q=a%26b%20c");
+
+        let mut page = page();
+        page.set_escape(Escape::None);
+        let mut ctx = Context::new();
+        ctx.set_value("user", "<b>");
+        ctx.set_value("text", "<i>");
+        assert_eq!(page.render_with(&ctx).unwrap(), "<p><b></p><i><i></i>");
+    }
+}
+
+#[cfg(test)]
+mod html_context_escape_tests {
+    use super::*;
+
+    fn page(body: &str) -> NestedTemplate {
+        let mut page = NestedTemplate::new(body);
+        page.set_name("page.html");
+        page
+    }
+
+    #[test]
+    fn test_escaper_follows_context() {
+        let page = page(concat!(
+            "<a href=\"{url}?q={query}\" title={title}>{text}</a>",
+            "<script>var name = '{text}';</script><style>p {{ font-family: {font}; }}</style>",
+        ));
+        let mut ctx = Context::new();
+        ctx.set_value("url", "/search");
+        ctx.set_value("query", "a&b");
+        ctx.set_value("title", "x onclick=y");
+        ctx.set_value("text", "</script>'");
+        ctx.set_value("font", "a;}");
+        assert_eq!(
+            page.render_with(&ctx).unwrap(),
+            concat!(
+                "<a href=\"/search?q=a%26b\" title=x&#x20;onclick&#x3D;y>&lt;/script&gt;&#x27;</a>",
+                "<script>var name = '\\u003C/script\\u003E\\u0027';</script>",
+                "<style>p { font-family: a\\3B \\7D ; }</style>",
+            )
+        );
+    }
+
+    #[test]
+    fn test_sub_template_in_script() {
+        let mut page = page("<script>let s = \"{child}\";</script>");
+        let mut child = NestedTemplate::new("<b>{greeting}</b>");
+        child.set_value("greeting", "\"hi\" & bye");
+        page.add_sub_template("child", child);
+        assert_eq!(
+            page.render().unwrap(),
+            "<script>let s = \"\\u003Cb\\u003E\\u0022hi\\u0022 \\u0026 bye\\u003C/b\\u003E\";</script>"
+        );
+    }
+
+    #[test]
+    fn test_unsafe_placement_is_rejected() {
+        let mut page = page("<p>ok</p>\n<script>{code}</script>");
+        page.set_value("code", "alert(1)");
+        match page.render() {
+            Err(ParseError::Unsafe(loc, _)) => {
+                assert_eq!((loc.line, loc.column), (2, 10));
+                assert_eq!(loc.template.as_deref(), Some("page.html"));
+            }
+            other => panic!("unexpected result {:?}", other),
         }
+        assert!(matches!(&page.validate()[..], [ParseError::Unsafe(..)]));
+
+        page.set_value("code", Value::safe("alert(1)"));
+        assert_eq!(
+            page.render().unwrap(),
+            "<p>ok</p>\n<script>alert(1)</script>"
+        );
+        assert!(page.validate().is_empty());
     }
 
     #[test]
-    fn test_empty_template_with_helper() {
+    fn test_script_the_scanner_follows() {
+        for body in [
+            "<script>// don't\nvar x = {v};</script>",
+            "<script>var r = /'/; var x = {v};</script>",
+            "<iframe srcdoc=\"{v}\"></iframe>",
+        ] {
+            let mut page = page(body);
+            page.set_value("v", "alert(1)");
+            assert!(
+                matches!(page.render(), Err(ParseError::Unsafe(..))),
+                "{}",
+                body
+            );
+            assert!(matches!(&page.validate()[..], [ParseError::Unsafe(..)]));
+        }
+
+        let mut link = page("<svg><a xlink:href=\"{v}\">link</a></svg>");
+        link.set_value("v", "javascript:alert(1)");
+        assert!(matches!(link.render(), Err(ParseError::Unsafe(..))));
+
+        let mut script = page("<script>let s = `{v}`;</script>");
+        script.set_value("v", "${alert(1)}");
         assert_eq!(
-            render_helper("{}").unwrap(),
-            vec![
-                (false, String::new()),
-                (true, String::new()),
-                (false, String::new())
-            ]
+            script.render().unwrap(),
+            "<script>let s = `\\u0024{alert(1)}`;</script>"
+        );
+    }
+
+    #[test]
+    fn test_if_branches_ending_in_different_contexts() {
+        let mut page = page("{#if a}<p>{:else}<script>{/if}{v}</script>");
+        page.set_value("a", "");
+        page.set_value("v", "alert(1)");
+        assert!(matches!(page.render(), Err(ParseError::Unsafe(..))));
+        assert!(matches!(&page.validate()[..], [ParseError::Unsafe(..)]));
+    }
+
+    #[test]
+    fn test_each_items_ending_in_another_context() {
+        let mut page = page("{#each xs as x}{v}<script>{/each}");
+        page.set_value("xs", Value::from(vec!["a", "b"]));
+        page.set_value("v", "alert(1)");
+        assert!(matches!(page.render(), Err(ParseError::Unsafe(..))));
+        assert!(matches!(&page.validate()[..], [ParseError::Unsafe(..)]));
+    }
+
+    #[test]
+    fn test_unsafe_url_scheme_is_rejected() {
+        let mut page = page("<a href=\"{url}\">link</a>");
+        page.set_value("url", "javascript:alert(1)");
+        assert!(matches!(page.render(), Err(ParseError::Unsafe(..))));
+    }
+}
+
+#[cfg(test)]
+mod if_block_tests {
+    use super::*;
+
+    fn nav() -> NestedTemplate {
+        NestedTemplate::new(
+            "<nav>{#if user}Hi {user.name}{:else}<a href=\"/login\">Log in</a>{/if}{#if !admin} (guest){/if}</nav>",
+        )
+    }
+
+    #[test]
+    fn test_if_on_context_values() {
+        let nav = nav();
+        assert_eq!(
+            nav.render().unwrap(),
+            "<nav><a href=\"/login\">Log in</a> (guest)</nav>"
         );
+
+        let mut ctx = Context::new();
+        ctx.set_value("user", [("name", "Ann")].into_iter().collect::<Value>());
+        ctx.set_value("admin", true);
+        assert_eq!(
+            nav.compile().unwrap().render_with(&ctx).unwrap(),
+            "<nav>Hi Ann</nav>"
+        );
+    }
+
+    #[test]
+    fn test_if_on_sub_templates_and_falsy_values() {
+        let mut template =
+            NestedTemplate::new("{#if extra}[{extra}]{/if}{#if count}{count}{:else}none{/if}");
+        template.set_value("count", 0);
+        assert_eq!(template.render().unwrap(), "none");
+
+        template.add_sub_template("extra", NestedTemplate::new("x"));
+        template.set_value("count", Vec::<Value>::new());
+        assert_eq!(template.render().unwrap(), "[x]none");
+    }
+
+    #[test]
+    fn test_validate_if_blocks() {
+        let template = NestedTemplate::new("{#if missing}{other}{/if}{:else}");
+        let errors = template.validate();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ParseError::UnexpectedTag(loc) if loc.offset == 25));
+        assert!(matches!(&errors[1], ParseError::MissingTemplate(name) if name == "other"));
+    }
+}
+
+#[cfg(test)]
+mod each_block_tests {
+    use super::*;
+
+    fn users() -> Value {
+        let ann: Value = [("name", "Ann")].into_iter().collect();
+        let bob: Value = [("name", "Bob")].into_iter().collect();
+        Value::from(vec![ann, bob])
     }
 
     #[test]
-    fn test_escape_with_for_helper() {
+    fn test_each_with_loop_metadata() {
+        let template = NestedTemplate::new(
+            "{#each users as user}{loop.number}. {user.name}{#if !loop.last}, {/if}{:empty}nobody{/each}",
+        );
+        let mut ctx = Context::new();
+        ctx.set_value("users", users());
+        assert_eq!(template.render_with(&ctx).unwrap(), "1. Ann, 2. Bob");
+
+        ctx.set_value("users", Vec::<Value>::new());
         assert_eq!(
-            render_helper("this should {{ be escaped }}").unwrap(),
-            vec![
-                (false, "this should ".to_string()),
-                (false, "{".to_string()),
-                (false, " be escaped ".to_string()),
-                (false, "}".to_string()),
-                (false, String::new())
-            ]
+            template.compile().unwrap().render_with(&ctx).unwrap(),
+            "nobody"
+        );
+    }
+
+    #[test]
+    fn test_each_renders_sub_template_per_item() {
+        let mut table = NestedTemplate::new("<table>{#each users as user}{row}{/each}</table>");
+        table.add_sub_template(
+            "row",
+            NestedTemplate::new("<tr><td>{loop.index}</td><td>{user.name}</td></tr>"),
+        );
+        table.set_value("users", users());
+        assert_eq!(
+            table.render().unwrap(),
+            "<table><tr><td>0</td><td>Ann</td></tr><tr><td>1</td><td>Bob</td></tr></table>"
+        );
+        assert!(table.validate().is_empty());
+    }
+
+    #[test]
+    fn test_nested_each() {
+        let template = NestedTemplate::new(
+            "{#each rows as row}[{#each row as cell}{cell}{/each}:{loop.index}]{/each}",
+        );
+        let mut ctx = Context::new();
+        ctx.set_value("rows", vec![vec![1, 2], vec![3]]);
+        assert_eq!(template.render_with(&ctx).unwrap(), "[12:0][3:1]");
+    }
+
+    #[test]
+    fn test_each_errors() {
+        let mut template = NestedTemplate::new("{#each users as user}{user}{/each}");
+        template.set_value("users", "Ann");
+        assert!(
+            matches!(template.render(), Err(ParseError::NotList(name, "string")) if name == "users")
+        );
+
+        let errors = NestedTemplate::new("{#each users as user}{user}{other}{/each}").validate();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ParseError::MissingTemplate(name) if name == "users"));
+        assert!(matches!(&errors[1], ParseError::MissingTemplate(name) if name == "other"));
+    }
+}
+
+#[cfg(test)]
+mod inheritance_tests {
+    use super::*;
+
+    fn base() -> NestedTemplate {
+        NestedTemplate::new(
+            "<title>{block title}Site{/block}</title><main>{block content}Nothing here{/block}</main>{footer}",
+        )
+    }
+
+    #[test]
+    fn test_child_overrides_blocks() {
+        let mut child = NestedTemplate::new(
+            "{% extends \"base\" %}\nignored\n{block title}{page} - {super}{/block}",
+        );
+        child.set_value("page", "About");
+        let mut base = base();
+        base.add_sub_template(
+            "footer",
+            NestedTemplate::new("<footer>{block title}x{/block}</footer>"),
+        );
+        child.add_sub_template("base", base);
+
+        let expected = "<title>About - Site</title><main>Nothing here</main><footer>x</footer>";
+        assert_eq!(child.render().unwrap(), expected);
+        assert_eq!(child.compile().unwrap().render().unwrap(), expected);
+        assert!(child.validate().is_empty());
+    }
+
+    #[test]
+    fn test_multi_level_inheritance() {
+        let mut ctx = Context::new();
+        let mut base = base();
+        base.set_value("footer", "");
+        ctx.add_sub_template("base", base);
+        ctx.add_sub_template(
+            "layout",
+            NestedTemplate::new("{% extends base %}{block content}<div>{super}</div>{/block}"),
+        );
+
+        let page = NestedTemplate::new(
+            "{% extends \"layout\" %}{block content}<p>{super}</p>{/block}{block title}Page{/block}",
+        );
+        assert_eq!(
+            page.render_with(&ctx).unwrap(),
+            "<title>Page</title><main><p><div>Nothing here</div></p></main>"
+        );
+    }
+
+    // A page that fills the blocks of an HTML base with `blocks`
+    fn html_page(base: &str, blocks: &str) -> NestedTemplate {
+        let mut base = NestedTemplate::new(base);
+        base.set_name("base.html");
+        let mut page = NestedTemplate::new(&format!("{{% extends \"base\" %}}{}", blocks));
+        page.add_sub_template("base", base);
+        page
+    }
+
+    #[test]
+    fn test_blocks_are_escaped_where_they_land() {
+        let page = html_page(
+            "<a href=\"{block url}/{/block}\">{block label}Home{/block}</a>",
+            "{block url}{link}{/block}{block label}<b>{super}</b> {link}{/block}",
+        );
+        let mut ctx = Context::new();
+        ctx.set_value("link", "/about?a=1&b");
+        let expected = "<a href=\"/about?a=1&amp;b\"><b>Home</b> /about?a=1&amp;b</a>";
+        assert_eq!(page.render_with(&ctx).unwrap(), expected);
+        assert_eq!(page.compile().unwrap().render_with(&ctx).unwrap(), expected);
+
+        ctx.set_value("link", "javascript:alert(1)");
+        assert!(matches!(
+            page.render_with(&ctx),
+            Err(ParseError::Unsafe(..))
+        ));
+    }
+
+    #[test]
+    fn test_blocks_in_script() {
+        let page = html_page(
+            "<script>var msg = \"{block msg}{/block}\";</script>",
+            "{block msg}{text}{/block}",
+        );
+        let mut ctx = Context::new();
+        ctx.set_value("text", "a\\b\n\"</script>");
+        assert_eq!(
+            page.render_with(&ctx).unwrap(),
+            "<script>var msg = \"a\\\\b\\n\\u0022\\u003C/script\\u003E\";</script>"
+        );
+        assert!(page.validate_with(&ctx).is_empty());
+
+        // Outside of a string the block can't be escaped at all
+        let page = html_page(
+            "<script>{block code}{/block}</script>",
+            "{block code}{text}{/block}",
+        );
+        assert!(matches!(
+            page.render_with(&ctx),
+            Err(ParseError::Unsafe(..))
+        ));
+        let errors = page.validate_with(&ctx);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], ParseError::Unsafe(..)));
+    }
+
+    #[test]
+    fn test_extends_errors() {
+        let mut page = NestedTemplate::new("{% extends \"base\" %}");
+        assert!(matches!(page.render(), Err(ParseError::MissingTemplate(name)) if name == "base"));
+        assert_eq!(page.validate().len(), 1);
+
+        page.set_value("base", "text");
+        assert!(
+            matches!(page.render(), Err(ParseError::NotTemplate(name, "string")) if name == "base")
+        );
+    }
+}
+
+#[cfg(test)]
+mod default_placeholder_tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_placeholders_fall_back() {
+        let mut layout = NestedTemplate::new(
+            "<head>{extra_head?}</head><h1>{title | default: \"Untitled\"}</h1>{user.name?}",
+        );
+        assert_eq!(layout.render().unwrap(), "<head></head><h1>Untitled</h1>");
+        assert_eq!(
+            layout.compile().unwrap().render().unwrap(),
+            "<head></head><h1>Untitled</h1>"
+        );
+        assert!(layout.validate().is_empty());
+
+        layout.set_value("title", "Home");
+        layout.add_sub_template("extra_head", NestedTemplate::new("<meta>"));
+        assert_eq!(layout.render().unwrap(), "<head><meta></head><h1>Home</h1>");
+    }
+
+    #[test]
+    fn test_default_does_not_hide_nested_errors() {
+        let mut layout = NestedTemplate::new("{extra_head?}");
+        layout.add_sub_template("extra_head", NestedTemplate::new("{missing}"));
+        assert!(
+            matches!(layout.render(), Err(ParseError::MissingTemplate(name)) if name == "missing")
         );
+        assert_eq!(layout.validate().len(), 1);
     }
 
     #[test]
-    fn test_render_helper() {
-        let template_str = "{ template }{other_template} not template {{}}";
+    fn test_default_is_not_escaped() {
+        let mut page = NestedTemplate::new("<p>{note | default: \"<em>none</em>\"}</p>");
+        page.set_escape(Escape::Html);
+        assert_eq!(page.render().unwrap(), "<p><em>none</em></p>");
+
+        page.set_value("note", "<b>");
+        assert_eq!(page.render().unwrap(), "<p>&lt;b&gt;</p>");
+    }
+}
+
+#[cfg(test)]
+mod missing_policy_tests {
+    use super::*;
+
+    fn page() -> NestedTemplate {
+        let mut page = NestedTemplate::new("<p>{greeting}, {user.name}{sign_off?}</p>");
+        page.set_escape(Escape::Html);
+        page
+    }
+
+    #[test]
+    fn test_error_is_the_default() {
+        let ctx = Context::new();
+        assert!(matches!(
+            page().render_with(&ctx),
+            Err(ParseError::MissingTemplate(name)) if name == "greeting"
+        ));
+        assert_eq!(page().validate_with(&ctx).len(), 2);
+    }
+
+    #[test]
+    fn test_empty_and_keep() {
+        let mut ctx = Context::new();
+        ctx.set_missing(Missing::Empty);
+        assert_eq!(page().render_with(&ctx).unwrap(), "<p>, </p>");
+        assert!(page().validate_with(&ctx).is_empty());
+
+        ctx.set_missing(Missing::Keep);
+        ctx.set_value("greeting", "Hi");
+        let first_pass = page().render_with(&ctx).unwrap();
+        assert_eq!(first_pass, "<p>Hi, {user.name}</p>");
+
+        let mut second = NestedTemplate::new(&first_pass);
+        second.set_value("user", [("name", "Ann")].into_iter().collect::<Value>());
+        assert_eq!(second.render().unwrap(), "<p>Hi, Ann</p>");
+    }
+
+    #[test]
+    fn test_callback() {
+        let mut ctx = Context::new();
+        ctx.set_missing(Missing::Callback(Box::new(|name| {
+            (name != "greeting").then(|| format!("<{}>", name))
+        })));
+        assert!(matches!(
+            page().render_with(&ctx),
+            Err(ParseError::MissingTemplate(name)) if name == "greeting"
+        ));
+
+        ctx.set_value("greeting", "Hi");
         assert_eq!(
-            render_helper(template_str).unwrap(),
-            vec![
-                (false, String::new()),
-                (true, "template".to_string()),
-                (false, String::new()),
-                (true, "other_template".to_string()),
-                (false, " not template ".to_string()),
-                (false, "{".to_string()),
-                (false, String::new()),
-                (false, "}".to_string()),
-                (false, String::new()),
+            page().compile().unwrap().render_with(&ctx).unwrap(),
+            "<p>Hi, &lt;user.name&gt;</p>"
+        );
+    }
+}
+
+#[cfg(test)]
+mod filter_pipe_tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_filters() {
+        let mut page =
+            NestedTemplate::new("{title | trim | upper | truncate(5)} {when | date(\"%d %b %Y\")}");
+        page.set_value("title", "  hello world ");
+        page.set_value("when", 1709649000);
+        assert_eq!(page.render().unwrap(), "HELLO... 05 Mar 2024");
+        assert_eq!(
+            page.compile().unwrap().render().unwrap(),
+            "HELLO... 05 Mar 2024"
+        );
+        assert!(page.validate().is_empty());
+    }
+
+    #[test]
+    fn test_filters_apply_to_templates_and_defaults() {
+        let mut page =
+            NestedTemplate::new("{header | lower}|{missing | upper | default: \"None\" | upper}");
+        page.add_sub_template("header", NestedTemplate::new("Hi {name}"));
+        let mut ctx = Context::new();
+        ctx.set_value("name", "ANN");
+        assert_eq!(page.render_with(&ctx).unwrap(), "hi ann|NONE");
+    }
+
+    #[test]
+    fn test_json_filter() {
+        let mut page = NestedTemplate::new("<script>var data = {data | json};</script>");
+        page.set_escape(Escape::Html);
+        let data: Value = [("tags", Value::from(vec!["a</script>", "b"]))]
+            .into_iter()
+            .collect();
+        page.set_value("data", data);
+        // JSON is not a string literal, so it has to be marked safe to go into script
+        assert!(matches!(page.render(), Err(ParseError::Unsafe(..))));
+
+        let mut page = NestedTemplate::new("{data | json}");
+        page.set_value(
+            "data",
+            vec![Value::Null, Value::from(1.5), Value::from("\"")],
+        );
+        assert_eq!(page.render().unwrap(), "[null,1.5,\"\\\"\"]");
+    }
+
+    #[test]
+    fn test_escape_and_safe() {
+        let mut page = NestedTemplate::new(
+            "<a title=\"{name | escape | upper}\">{name | safe}</a>{name | escape}",
+        );
+        page.set_value("name", "<b>");
+        assert_eq!(
+            page.render().unwrap(),
+            "<a title=\"&LT;B&GT;\"><b></a>&lt;b&gt;"
+        );
+
+        page.set_escape(Escape::Html);
+        assert_eq!(
+            page.render().unwrap(),
+            "<a title=\"&LT;B&GT;\"><b></a>&lt;b&gt;"
+        );
+    }
+
+    #[test]
+    fn test_custom_filters() {
+        let mut page = NestedTemplate::new("{name | shout(3)} {nav}");
+        page.set_value("name", "hey");
+        page.register_filter("shout", |text, args| match args {
+            [Arg::Int(n)] => Ok(format!("{}{}", text, "!".repeat(*n as usize))),
+            _ => Err("expects a count".to_string()),
+        });
+        // Sub-templates see the filters of the templates they are inserted into
+        let mut nav = NestedTemplate::new("{link | shout(1) | upper}");
+        nav.register_filter("upper", |text, _| Ok(format!("[{}]", text)));
+        nav.set_value("link", "home");
+        page.add_sub_template("nav", nav);
+
+        assert_eq!(page.render().unwrap(), "hey!!! [home!]");
+        assert!(page.validate().is_empty());
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        let mut page = NestedTemplate::new("{name | nope} {name | truncate}");
+        page.set_value("name", "x");
+        assert!(matches!(page.render(), Err(ParseError::UnknownFilter(name)) if name == "nope"));
+        let errors = page.validate();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], ParseError::UnknownFilter(name) if name == "nope"));
+
+        page.register_filter("nope", |text, _| Ok(text.to_string()));
+        assert_eq!(
+            page.render().unwrap_err().to_string(),
+            "Filter truncate failed: expects a length and an optional end string"
+        );
+    }
+
+    #[test]
+    fn test_keep_leaves_filters_in_place() {
+        let mut ctx = Context::new();
+        ctx.set_missing(Missing::Keep);
+        let page = NestedTemplate::new("a { name | upper } b");
+        assert_eq!(page.render_with(&ctx).unwrap(), "a { name | upper } b");
+    }
+}
+
+#[cfg(test)]
+mod raw_region_tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_regions_render_verbatim() {
+        let mut page = NestedTemplate::new(
+            "<script>var config = {raw}{\"a\": [1, {}]}{/raw};</script><p>{name}</p>",
+        );
+        page.set_escape(Escape::Html);
+        page.set_value("name", "<b>");
+        let expected = "<script>var config = {\"a\": [1, {}]};</script><p>&lt;b&gt;</p>";
+        assert_eq!(page.render().unwrap(), expected);
+        assert_eq!(page.compile().unwrap().render().unwrap(), expected);
+        assert!(page.validate().is_empty());
+    }
+
+    #[test]
+    fn test_unclosed_raw_region() {
+        let page = NestedTemplate::new("a\n  {raw} {b}");
+        let errors = page.validate();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].to_string(),
+            "Block opened at 2:3 is never closed\n  {raw} {b}\n  ^"
+        );
+        assert!(matches!(&errors[1], ParseError::MissingTemplate(name) if name == "b"));
+    }
+}
+
+#[cfg(test)]
+mod comment_tests {
+    use super::*;
+
+    #[test]
+    fn test_comments_are_stripped_and_not_validated() {
+        let page = NestedTemplate::new(
+            "<p>{# greeting goes here #}Hi{#\n  {name | upper}\n  {# ignored}\n#}</p>",
+        );
+        assert_eq!(page.render().unwrap(), "<p>Hi</p>");
+        assert_eq!(page.compile().unwrap().render().unwrap(), "<p>Hi</p>");
+        assert!(page.validate().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod whitespace_tests {
+    use super::*;
+
+    fn list() -> NestedTemplate {
+        let mut list = NestedTemplate::new(
+            "<ul>\n    {#each items as item}\n    <li>{item}</li>\n    {/each}\n</ul>\n",
+        );
+        list.set_value("items", vec!["a", "b"]);
+        list
+    }
+
+    #[test]
+    fn test_trim_markers() {
+        let mut page = NestedTemplate::new("<p>\n  {- greeting -}\n</p>");
+        page.set_value("greeting", "Hi");
+        assert_eq!(page.render().unwrap(), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn test_block_options() {
+        assert_eq!(
+            list().render().unwrap(),
+            "<ul>\n    \n    <li>a</li>\n    \n    <li>b</li>\n    \n</ul>\n"
+        );
+
+        let mut list = list();
+        list.set_trim_blocks(true);
+        list.set_lstrip_blocks(true);
+        let expected = "<ul>\n    <li>a</li>\n    <li>b</li>\n</ul>\n";
+        assert_eq!(list.render().unwrap(), expected);
+        assert_eq!(list.compile().unwrap().render().unwrap(), expected);
+    }
+}
+
+#[cfg(test)]
+mod auto_indent_tests {
+    use super::*;
+
+    #[test]
+    fn test_sub_templates_are_indented() {
+        let mut config = NestedTemplate::new("services:\n  web:\n    {web}\n  db: {db}\n");
+        config.set_auto_indent(true);
+        let mut web = NestedTemplate::new("image: nginx\nports:\n  {ports}\n");
+        web.set_value("ports", "- 80\n\n- 443");
+        config.add_sub_template("web", web);
+        config.set_value("db", "image: postgres\nrestart: always");
+
+        let expected = "services:\n  web:\n    image: nginx\n    ports:\n      - 80\n\n      - 443\n\n  db: image: postgres\n      restart: always\n";
+        assert_eq!(config.render().unwrap(), expected);
+        assert_eq!(config.compile().unwrap().render().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_indent_is_relative_to_the_template() {
+        let mut page = NestedTemplate::new("<body>\n\t<div>{inner}</div>\n</body>");
+        page.set_auto_indent(true);
+        page.set_escape(Escape::Html);
+        // The inner template starts partway through a line of the outer one
+        let mut inner = NestedTemplate::new("<p>\n  {text}\n</p>");
+        inner.set_safe(true);
+        inner.set_value("text", "a\nb");
+        page.add_sub_template("inner", inner);
+
+        assert_eq!(
+            page.render().unwrap(),
+            "<body>\n\t<div><p>\n\t       a\n\t       b\n\t     </p></div>\n</body>"
+        );
+    }
+
+    #[test]
+    fn test_auto_indent_is_off_by_default() {
+        let mut page = NestedTemplate::new("  {text}");
+        page.set_value("text", "a\nb");
+        assert_eq!(page.render().unwrap(), "  a\nb");
+
+        // And can be turned off again below a template that turns it on
+        let mut outer = NestedTemplate::new("  {page}");
+        outer.set_auto_indent(true);
+        page.set_auto_indent(false);
+        outer.add_sub_template("page", page);
+        assert_eq!(outer.render().unwrap(), "    a\n  b");
+    }
+}
+
+#[cfg(test)]
+mod cycle_tests {
+    use super::*;
+
+    #[test]
+    fn test_cycles_are_errors() {
+        let mut registry = Registry::new();
+        registry.add_template("a", NestedTemplate::new("a{b}"));
+        registry.add_template("b", NestedTemplate::new("b{c}"));
+        registry.add_template("c", NestedTemplate::new("c{a}"));
+
+        let err = registry.render("a").unwrap_err();
+        assert!(matches!(&err, ParseError::Cycle(chain) if chain == &["a", "b", "c", "a"]));
+        assert_eq!(
+            err.to_string(),
+            "Template includes itself: a -> b -> c -> a"
+        );
+
+        // Extending counts as well, once `b` includes `c` a second time
+        registry.add_template("c", NestedTemplate::new("{% extends b %}"));
+        assert!(matches!(
+            registry.render("b"),
+            Err(ParseError::Cycle(chain)) if chain == ["b", "c", "b", "c"]
+        ));
+        registry.add_template("a", NestedTemplate::new("{% extends c %}"));
+        registry.add_template("c", NestedTemplate::new("{% extends a %}"));
+        assert!(matches!(
+            registry.render("a"),
+            Err(ParseError::Cycle(chain)) if chain == ["a", "c", "a"]
+        ));
+    }
+
+    #[test]
+    fn test_the_same_template_twice_is_not_a_cycle() {
+        let mut registry = Registry::new();
+        registry.add_template("item", NestedTemplate::new("<li>"));
+        registry.add_template("list", NestedTemplate::new("{item}{item}"));
+        assert_eq!(registry.render("list").unwrap(), "<li><li>");
+
+        // Nor is a layout that a template inside it extends as well
+        registry.add_template("box", NestedTemplate::new("[{block content}{/block}]"));
+        registry.add_template(
+            "widget",
+            NestedTemplate::new("{% extends box %}{block content}w{/block}"),
+        );
+        registry.add_template(
+            "page",
+            NestedTemplate::new("{% extends box %}{block content}p{widget}{/block}"),
+        );
+        assert_eq!(registry.render("page").unwrap(), "[p[w]]");
+        assert!(registry.validate().is_empty());
+    }
+
+    #[test]
+    fn test_max_depth_allows_recursion() {
+        let mut registry = Registry::new();
+        // Each level binds `node` to a child, which the nested menu then loops over
+        registry.add_template(
+            "menu",
+            NestedTemplate::new(
+                "<ul>{#each node.items as node}<li>{node.label}{#if node.items}{menu}{/if}</li>{/each}</ul>",
+            ),
+        );
+        let node = |label: &str, items: Vec<Value>| -> Value {
+            [("label", Value::from(label)), ("items", Value::from(items))]
+                .into_iter()
+                .collect()
+        };
+        let tree = node(
+            "",
+            vec![node("a", vec![node("b", vec![])]), node("c", vec![])],
+        );
+
+        let mut ctx = Context::new();
+        ctx.set_value("node", tree);
+        assert!(matches!(
+            registry.render_with("menu", &ctx),
+            Err(ParseError::Cycle(_))
+        ));
+
+        ctx.set_max_depth(Some(8));
+        assert_eq!(
+            registry.render_with("menu", &ctx).unwrap(),
+            "<ul><li>a<ul><li>b</li></ul></li><li>c</li></ul>"
+        );
+
+        // Without a `node` to loop over at each level, this menu never ends
+        registry.add_template("menu", NestedTemplate::new("{menu}"));
+        let mut page = NestedTemplate::new("{menu}");
+        page.set_name("page");
+        let err = registry.render_template(&page, &ctx).unwrap_err();
+        match err {
+            ParseError::TooDeep(8, chain) => {
+                assert_eq!(chain.len(), 9);
+                assert_eq!(chain[..2], ["page", "menu"]);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod syntax_tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_delimiters() {
+        let mut style =
+            NestedTemplate::new("body { color: [[ color | default: \"red\" ]]; } [[[[ ]]");
+        style.set_syntax(Syntax::new("[[", "]]"));
+        assert_eq!(style.render().unwrap(), "body { color: red; } [[ ]]");
+
+        // Sub-templates are parsed with their own delimiters
+        let mut page = NestedTemplate::new("<style>{style}</style>");
+        page.add_sub_template("style", style);
+        assert_eq!(
+            page.compile().unwrap().render().unwrap(),
+            "<style>body { color: red; } [[ ]]</style>"
+        );
+        assert!(page.validate().is_empty());
+    }
+
+    #[test]
+    fn test_keep_uses_delimiters() {
+        let mut ctx = Context::new();
+        ctx.set_missing(Missing::Keep);
+        let mut page = NestedTemplate::new("{a} <%= b | upper %> {c}");
+        page.set_syntax(Syntax::new("<%=", "%>"));
+        assert_eq!(page.render_with(&ctx).unwrap(), "{a} <%= b | upper %> {c}");
+    }
+
+    #[test]
+    fn test_custom_delimiter_errors() {
+        let mut page = NestedTemplate::new("line\n<% a <% b %>");
+        page.set_syntax(Syntax::new("<%", "%>"));
+        assert_eq!(
+            page.render().unwrap_err().to_string(),
+            "Open brace at 2:1 does not have a corresponding close brace\n<% a <% b %>\n^"
+        );
+    }
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_valid_tree() {
+        let mut parent = NestedTemplate::new("<body>{child}{{}}</body>");
+        parent.add_sub_template("child", NestedTemplate::new("child"));
+        assert!(parent.validate().is_empty());
+    }
+
+    #[test]
+    fn test_validate_collects_everything() {
+        let mut parent = NestedTemplate::new("{} } {missing} {child}");
+        parent.add_sub_template("child", NestedTemplate::new("{ unclosed"));
+
+        let errors = parent.validate();
+        assert_eq!(errors.len(), 4);
+        assert!(matches!(&errors[0], ParseError::MissingOpenBrace(loc) if loc.offset == 3));
+        assert!(matches!(&errors[1], ParseError::EmptyPlaceholder(loc) if loc.offset == 0));
+        assert!(matches!(&errors[2], ParseError::MissingTemplate(name) if name == "missing"));
+        match &errors[3] {
+            ParseError::MissingCloseBrace(loc) => {
+                assert_eq!(loc.template.as_deref(), Some("child"))
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod compiled_template_tests {
+    use super::*;
+
+    fn page() -> NestedTemplate {
+        let mut parent = NestedTemplate::new("<!DOCTYPE html><body>{ first_child }{{}}</body>");
+        let mut first_child = NestedTemplate::new("<div>{{ {second_child} }}</div>");
+        first_child.add_sub_template("second_child", NestedTemplate::new("second_child"));
+        parent.add_sub_template("first_child", first_child);
+        parent
+    }
+
+    #[test]
+    fn test_compiled_matches_render() {
+        let parent = page();
+        let compiled = parent.compile().unwrap();
+        assert_eq!(compiled.render().unwrap(), parent.render().unwrap());
+        assert_eq!(compiled.render().unwrap(), compiled.render().unwrap());
+    }
+
+    #[test]
+    fn test_compiled_nodes_borrow_body() {
+        let template = NestedTemplate::new("a {{ {b} }}");
+        let compiled = template.compile().unwrap();
+        assert_eq!(
+            compiled.nodes(),
+            &[
+                Node::Literal("a "),
+                Node::Literal("{"),
+                Node::Literal(" "),
+                Node::Placeholder {
+                    name: "b",
+                    filters: vec![],
+                },
+                Node::Literal(" "),
+                Node::Literal("}"),
             ]
         );
     }
+
+    #[test]
+    fn test_compiled_missing_template() {
+        let template = NestedTemplate::new("{missing}");
+        match template.compile().unwrap().render() {
+            Err(ParseError::MissingTemplate(name)) => assert_eq!(name, "missing"),
+            _ => panic!("CompiledTemplate.render() did not report the missing template"),
+        }
+    }
+
+    #[test]
+    fn test_compile_reports_parse_errors() {
+        match NestedTemplate::new("text }").compile() {
+            Err(ParseError::MissingOpenBrace(_)) => (),
+            _ => panic!("NestedTemplate.compile() did not catch missing \"{{\""),
+        }
+    }
+
+    #[test]
+    fn test_errors_name_the_sub_template() {
+        let mut parent = NestedTemplate::new("{child}");
+        parent.add_sub_template("child", NestedTemplate::new("line one\nline {two"));
+
+        let err = parent.render().unwrap_err();
+        let loc = err.location().unwrap();
+        assert_eq!(loc.template.as_deref(), Some("child"));
+        assert_eq!((loc.offset, loc.line, loc.column), (14, 2, 6));
+        assert!(parent.compile().is_err());
+    }
 }
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use crate::watch::{Changes, Watcher};
+use crate::{Context, NestedTemplate, ParseError, Registry};
+
+/// Loads the files under a directory into a [`Registry`]. Each file is registered under its path
+/// relative to the directory, without the extension, so `templates/layout/base.html` is
+/// `layout/base`, and any template in the set can insert any other with a placeholder like
+/// `{layout/base}`.
+///
+/// Each template is given its relative path, extension included, as its name, so errors point at
+/// the file and `.html` files escape HTML. The files are trusted, so a template is not escaped
+/// when it is inserted into another.
+///
+/// The files are read and compiled once, when the loader is created. During development
+/// [`set_reload`](TemplateLoader::set_reload) makes the loader pick up changed, added and removed
+/// files before each render instead.
+#[derive(Debug)]
+pub struct TemplateLoader {
+    root: PathBuf,
+    registry: RwLock<Registry>,
+    reload: bool,
+    watch: Mutex<Watch>,
+}
+
+// What the loader keeps track of to reload files
+#[derive(Debug, Default)]
+struct Watch {
+    // Only there while reloading is on
+    watcher: Option<Watcher>,
+    // The name each loaded file is registered under
+    files: HashMap<PathBuf, String>,
+    // The files that failed to load the last time they changed, with the name they would be
+    // loaded under and the error. The root is in here with no name when it cannot be read
+    failed: HashMap<PathBuf, (String, io::ErrorKind, String)>,
+}
+
+impl TemplateLoader {
+    /// Loads every file under `root`, following subdirectories but not links to them. Files and
+    /// directories whose names start with a `.` are skipped. Fails if a file cannot be read or is
+    /// not UTF-8, or if two files would have the same name, like `a.html` and `a.txt`.
+    pub fn load(root: impl AsRef<Path>) -> io::Result<TemplateLoader> {
+        let root = root.as_ref().to_path_buf();
+        let mut registry = Registry::new();
+        let mut files = HashMap::new();
+        for path in list_files(&root)? {
+            let (name, template) = load_file(&root, &path)?;
+            if registry.get(&name).is_some() {
+                return Err(duplicate(&name));
+            }
+            registry.add_template(&name, template);
+            files.insert(path, name);
+        }
+        Ok(TemplateLoader {
+            root,
+            registry: RwLock::new(registry),
+            reload: false,
+            watch: Mutex::new(Watch {
+                files,
+                ..Watch::default()
+            }),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether to pick up files that changed before every render and validation, so edits show
+    /// up without a restart. Off by default, which keeps the templates as they were loaded.
+    ///
+    /// On Linux the directory is watched with inotify, so a render only looks at the files that
+    /// changed. Elsewhere, or when inotify cannot watch the directory, every file under it is
+    /// looked at before each render, which is fine for development but slow for many files.
+    ///
+    /// A changed file is compiled again when it is next needed. If it no longer parses, rendering
+    /// it fails with the parse error like any other template. If it cannot be read, rendering it
+    /// fails with [`ParseError::Reload`] and templates that insert it get the version that was
+    /// loaded last. Either way the file is picked up again once it is fixed.
+    pub fn set_reload(&mut self, reload: bool) {
+        self.reload = reload;
+        let watch = self.watch.get_mut().unwrap_or_else(PoisonError::into_inner);
+        // Anything that changed before the watcher started is found by looking at every file
+        watch.watcher = reload.then(|| Watcher::new(&self.root));
+        if reload {
+            let changes = Changes {
+                everything: true,
+                ..Changes::default()
+            };
+            apply(&self.root, &self.registry, watch, changes);
+        }
+    }
+
+    /// The loaded templates. Templates registered on the returned registry can use the loaded
+    /// ones and the other way around, but it does not see later reloads.
+    pub fn registry(&self) -> Registry {
+        self.registry
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The loaded templates, to register more by hand. These are kept when files are reloaded
+    /// unless a file is loaded under the same name.
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        self.registry
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_registry(self) -> Registry {
+        self.registry
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<NestedTemplate>> {
+        self.registry
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+
+    pub fn render(&self, name: &str) -> Result<String, ParseError> {
+        self.render_with(name, &Context::new())
+    }
+
+    /// Renders the template called `name`, looking placeholders up in `ctx` first like
+    /// [`NestedTemplate::render_with`] does.
+    pub fn render_with(&self, name: &str, ctx: &Context) -> Result<String, ParseError> {
+        if let Some(err) = self.refresh(Some(name)).into_iter().next() {
+            return Err(err);
+        }
+        self.registry
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .render_with(name, ctx)
+    }
+
+    pub fn validate(&self) -> Vec<ParseError> {
+        self.validate_with(&Context::new())
+    }
+
+    /// Validates every loaded template, in name order. Placeholders that `ctx` provides are not
+    /// reported as missing. Files that could not be reloaded are reported first.
+    pub fn validate_with(&self, ctx: &Context) -> Vec<ParseError> {
+        let mut errors = self.refresh(None);
+        errors.extend(
+            self.registry
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .validate_with(ctx),
+        );
+        errors
+    }
+
+    // Picks up the files that changed, if reloading is on, and returns the errors of the files
+    // called `name` that failed to load, or of every such file without a name
+    fn refresh(&self, name: Option<&str>) -> Vec<ParseError> {
+        if !self.reload {
+            return Vec::new();
+        }
+        let mut watch = self.watch.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(watcher) = &mut watch.watcher {
+            let changes = watcher.changes();
+            apply(&self.root, &self.registry, &mut watch, changes);
+        }
+
+        let mut failed: Vec<_> = watch
+            .failed
+            .iter()
+            .filter(|(_, (failed, ..))| name.is_none_or(|name| failed.is_empty() || failed == name))
+            .collect();
+        failed.sort_by_key(|(path, _)| *path);
+        failed
+            .into_iter()
+            .map(|(path, (_, kind, message))| {
+                let err = io::Error::new(*kind, message.as_str());
+                ParseError::Reload(path.display().to_string(), err)
+            })
+            .collect()
+    }
+}
+
+// Loads the files that changed, or every file, into the registry and drops the ones that are gone
+fn apply(root: &Path, registry: &RwLock<Registry>, watch: &mut Watch, changes: Changes) {
+    if changes.paths.is_empty() && !changes.everything {
+        return;
+    }
+
+    // Look at every file, or only the ones that changed and the ones that failed before,
+    // which may load now that another file with the same name is gone
+    let mut paths = changes.paths;
+    if changes.everything {
+        match list_files(root) {
+            Ok(files) => {
+                watch.failed.remove(root);
+                paths.extend(files);
+                paths.extend(watch.files.keys().cloned());
+            }
+            Err(err) => {
+                let failed = (String::new(), err.kind(), err.to_string());
+                watch.failed.insert(root.to_path_buf(), failed);
+                return;
+            }
+        }
+    }
+    paths.extend(watch.failed.keys().filter(|path| **path != root).cloned());
+
+    let mut registry = registry.write().unwrap_or_else(PoisonError::into_inner);
+    let mut load = Vec::new();
+    for path in paths {
+        if is_hidden(&path) {
+            continue;
+        }
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                if let Some(watcher) = &mut watch.watcher {
+                    watcher.add_dir(&path);
+                }
+                match list_files(&path) {
+                    Ok(files) => load.extend(files),
+                    Err(err) => {
+                        let failed = failure(root, &path, err);
+                        watch.failed.insert(path, failed);
+                    }
+                }
+            }
+            // Links to directories are not followed
+            Ok(_) if path.is_dir() => (),
+            Ok(_) => load.push(path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                remove_under(watch, &mut registry, &path)
+            }
+            Err(err) => {
+                let failed = failure(root, &path, err);
+                watch.failed.insert(path, failed);
+            }
+        }
+    }
+
+    // Loaded after the removals, so a file can take the name of one that is gone
+    load.sort();
+    load.dedup();
+    for path in load {
+        reload_file(root, watch, &mut registry, path);
+    }
+}
+
+fn reload_file(root: &Path, watch: &mut Watch, registry: &mut Registry, path: PathBuf) {
+    watch.failed.remove(&path);
+    let loaded = load_file(root, &path).and_then(|(name, template)| {
+        let taken = watch
+            .files
+            .iter()
+            .any(|(other, taken)| *other != path && *taken == name);
+        match taken {
+            true => Err(duplicate(&name)),
+            false => Ok((name, template)),
+        }
+    });
+    match loaded {
+        Ok((name, template)) => {
+            registry.add_template(&name, template);
+            watch.files.insert(path, name);
+        }
+        Err(err) => {
+            let failed = failure(root, &path, err);
+            watch.failed.insert(path, failed);
+        }
+    }
+}
+
+// How a file that failed to load is remembered: the name it would be loaded under and the error
+fn failure(root: &Path, path: &Path, err: io::Error) -> (String, io::ErrorKind, String) {
+    let name = match template_names(root, path) {
+        Ok((name, _)) => name,
+        Err(_) => path.display().to_string(),
+    };
+    (name, err.kind(), err.to_string())
+}
+
+// Drops the templates of `path` and every file under it, which are gone
+fn remove_under(watch: &mut Watch, registry: &mut Registry, path: &Path) {
+    watch.failed.retain(|failed, _| !failed.starts_with(path));
+    let gone: Vec<PathBuf> = watch
+        .files
+        .keys()
+        .filter(|file| file.starts_with(path))
+        .cloned()
+        .collect();
+    for file in gone {
+        if let Some(name) = watch.files.remove(&file) {
+            registry.remove(&name);
+        }
+    }
+}
+
+// Whether a file or directory is hidden, like editor swap files and `.git`
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+// Every file under `dir`, following subdirectories. Hidden files and directories, such as editor
+// swap files or `.git`, are left out, and so are links to directories so a link can't loop
+pub(crate) fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if is_hidden(&path) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(list_files(&path)?);
+        } else if !(file_type.is_symlink() && path.is_dir()) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn load_file(root: &Path, path: &Path) -> io::Result<(String, NestedTemplate)> {
+    let (name, file_name) = template_names(root, path)?;
+    let mut template = NestedTemplate::new(&fs::read_to_string(path)?);
+    template.set_name(&file_name);
+    template.set_safe(true);
+    Ok((name, template))
+}
+
+fn duplicate(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("more than one template is named {}", name),
+    )
+}
+
+// The name a file is loaded under and the name it reports errors under: its path relative to the
+// root with `/` between the parts, without and with the extension
+fn template_names(root: &Path, path: &Path) -> io::Result<(String, String)> {
+    let not_utf8 = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a UTF-8 path", path.display()),
+        )
+    };
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let parts = relative
+        .iter()
+        .map(|part| part.to_str().ok_or_else(not_utf8))
+        .collect::<io::Result<Vec<&str>>>()?;
+
+    let file_name = parts.join("/");
+    let name = match relative.extension() {
+        Some(extension) => &file_name[..file_name.len() - extension.len() - 1],
+        None => &file_name,
+    };
+    Ok((name.to_string(), file_name))
+}
+
+#[cfg(test)]
+mod loader_tests {
+    use super::*;
+
+    // A directory of templates under the system temp directory that is removed again on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> TempDir {
+            let dir = std::env::temp_dir().join(format!(
+                "nested-template-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            for (path, body) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, body).unwrap();
+            }
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_load_directory() {
+        let dir = TempDir::new(
+            "load",
+            &[
+                (
+                    "layout/base.html",
+                    "<main>{content}</main>{partials/footer}",
+                ),
+                ("partials/footer.html", "<footer>{year}</footer>"),
+                ("page.html", "{% extends \"layout/base\" %}{title}"),
+                ("notes", "plain {title}"),
+            ],
+        );
+        let loader = TemplateLoader::load(&dir.0).unwrap();
+        let registry = loader.registry();
+        let mut names: Vec<&str> = registry.names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["layout/base", "notes", "page", "partials/footer"]
+        );
+        assert_eq!(
+            loader.get("layout/base").unwrap().name(),
+            Some("layout/base.html")
+        );
+
+        let mut ctx = Context::new();
+        ctx.set_value("year", 2024);
+        ctx.set_value("content", "<Home>");
+        ctx.set_value("title", "<Home>");
+        assert_eq!(
+            loader.render_with("page", &ctx).unwrap(),
+            "<main>&lt;Home&gt;</main><footer>2024</footer>"
+        );
+        assert_eq!(loader.render_with("notes", &ctx).unwrap(), "plain <Home>");
+        assert!(loader.validate_with(&ctx).is_empty());
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let dir = TempDir::new("errors", &[("pages/about.html", "{missing}\n{ broken")]);
+        let loader = TemplateLoader::load(&dir.0).unwrap();
+        assert!(matches!(
+            loader.render("pages/nope"),
+            Err(ParseError::MissingTemplate(name)) if name == "pages/nope"
+        ));
+
+        let errors = loader.validate();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].location().unwrap().to_string(),
+            "pages/about.html:2:1"
+        );
+        assert!(matches!(&errors[1], ParseError::MissingTemplate(name) if name == "missing"));
+    }
+
+    #[test]
+    fn test_hidden_files_are_skipped() {
+        let dir = TempDir::new(
+            "hidden",
+            &[("page.html", "page"), (".git/config", "{ not a template")],
+        );
+        fs::write(dir.0.join(".DS_Store"), [0, 159, 146, 150]).unwrap();
+        fs::write(dir.0.join(".page.html.swp"), [0xff, 0xfe]).unwrap();
+        let loader = TemplateLoader::load(&dir.0).unwrap();
+        let registry = loader.registry();
+        assert_eq!(registry.names().collect::<Vec<_>>(), vec!["page"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_directory_links_are_not_followed() {
+        let dir = TempDir::new(
+            "links",
+            &[("pages/home.html", "home"), ("shared.html", "x")],
+        );
+        std::os::unix::fs::symlink(&dir.0, dir.0.join("pages/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.0.join("shared.html"), dir.0.join("pages/shared.html"))
+            .unwrap();
+        let loader = TemplateLoader::load(&dir.0).unwrap();
+        let registry = loader.registry();
+        let mut names: Vec<&str> = registry.names().collect();
+        names.sort();
+        assert_eq!(names, vec!["pages/home", "pages/shared", "shared"]);
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let dir = TempDir::new("duplicates", &[("a.html", ""), ("a.txt", "")]);
+        let err = TemplateLoader::load(&dir.0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_reload_changed_files() {
+        let dir = TempDir::new(
+            "reload",
+            &[("page.html", "<p>{title}</p>"), ("footer.html", "(c)")],
+        );
+        let mut loader = TemplateLoader::load(&dir.0).unwrap();
+        loader
+            .registry_mut()
+            .add_template("banner", NestedTemplate::new("!"));
+        loader.set_reload(true);
+        let mut ctx = Context::new();
+        ctx.set_value("title", "Home");
+        assert_eq!(loader.render_with("page", &ctx).unwrap(), "<p>Home</p>");
+
+        fs::write(dir.0.join("page.html"), "<h1>{title}</h1>{banner}{footer}").unwrap();
+        assert_eq!(
+            loader.render_with("page", &ctx).unwrap(),
+            "<h1>Home</h1>!(c)"
+        );
+
+        // A broken edit fails the render until the file is fixed
+        fs::write(dir.0.join("page.html"), "<h1>{title</h1>").unwrap();
+        assert!(matches!(
+            loader.render_with("page", &ctx),
+            Err(ParseError::MissingCloseBrace(loc)) if loc.to_string() == "page.html:1:5"
+        ));
+        assert_eq!(loader.validate_with(&ctx).len(), 1);
+        fs::write(dir.0.join("page.html"), "<h2>{title}</h2>{extra}").unwrap();
+        fs::create_dir(dir.0.join("parts")).unwrap();
+        fs::write(dir.0.join("parts/extra.html"), "+").unwrap();
+        fs::write(dir.0.join("extra.html"), "{parts/extra}").unwrap();
+        assert_eq!(loader.render_with("page", &ctx).unwrap(), "<h2>Home</h2>+");
+
+        fs::remove_file(dir.0.join("footer.html")).unwrap();
+        assert!(matches!(
+            loader.render("footer"),
+            Err(ParseError::MissingTemplate(name)) if name == "footer"
+        ));
+
+        // A file that can't be loaded only fails its own renders
+        fs::write(dir.0.join(".page.html.swp"), [0xff, 0xfe]).unwrap();
+        fs::write(dir.0.join("extra.txt"), "").unwrap();
+        fs::write(dir.0.join("binary.html"), [0xff, 0xfe]).unwrap();
+        assert_eq!(loader.render_with("page", &ctx).unwrap(), "<h2>Home</h2>+");
+        assert!(matches!(
+            loader.render("extra"),
+            Err(ParseError::Reload(..))
+        ));
+        assert!(matches!(
+            loader.render("binary"),
+            Err(ParseError::Reload(path, err))
+                if path.ends_with("binary.html") && err.kind() == io::ErrorKind::InvalidData
+        ));
+        assert_eq!(loader.validate_with(&ctx).len(), 2);
+
+        fs::remove_file(dir.0.join("extra.txt")).unwrap();
+        fs::write(dir.0.join("binary.html"), "fixed").unwrap();
+        assert_eq!(loader.render("extra").unwrap(), "+");
+        assert_eq!(loader.render("binary").unwrap(), "fixed");
+        assert!(loader.validate_with(&ctx).is_empty());
+    }
+
+    #[test]
+    fn test_frozen_without_reload() {
+        let dir = TempDir::new("frozen", &[("page.html", "before")]);
+        let loader = TemplateLoader::load(&dir.0).unwrap();
+        fs::write(dir.0.join("page.html"), "after, longer").unwrap();
+        fs::write(dir.0.join("new.html"), "new").unwrap();
+        assert_eq!(loader.render("page").unwrap(), "before");
+        assert!(loader.render("new").is_err());
+        assert!(loader.validate().is_empty());
+    }
+}
@@ -0,0 +1,1225 @@
+use crate::{Location, ParseError, Syntax};
+
+/// A single piece of a parsed template body. Literal text borrows directly from the body the
+/// template was compiled from, so rendering a compiled template does not allocate per segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node<'a> {
+    Literal(&'a str),
+    /// `{name | filter | filter(args)}`. A `default: "text"` filter gives the text rendered
+    /// instead when nothing is bound to `name`, and `{name?}` is short for `default: ""`
+    Placeholder {
+        name: &'a str,
+        filters: Vec<Filter<'a>>,
+    },
+    /// `{#if name}...{:else}...{/if}`. `negated` is set for `{#if !name}`
+    If {
+        condition: &'a str,
+        negated: bool,
+        then: Vec<Node<'a>>,
+        otherwise: Vec<Node<'a>>,
+    },
+    /// `{#each list as item}...{:empty}...{/each}`. `body` is rendered once per item of `list`
+    /// with `item` and `loop` bound, and `empty` is rendered when the list has no items
+    Each {
+        list: &'a str,
+        item: &'a str,
+        body: Vec<Node<'a>>,
+        empty: Vec<Node<'a>>,
+    },
+    /// `{% extends "name" %}`. The template named is rendered in place of this one, with the
+    /// blocks of this template replacing its blocks of the same name
+    Extends(&'a str),
+    /// `{block name}...{/block}`, a region a template that extends this one can replace
+    Block {
+        name: &'a str,
+        body: Vec<Node<'a>>,
+    },
+    /// `{super}` inside a block, which renders the block it replaces
+    Super,
+}
+
+/// One step of the pipe after a placeholder name, like `truncate(40)` in `{title | truncate(40)}`.
+/// Arguments go in parentheses or after a colon, so `truncate: 40` is the same filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter<'a> {
+    pub name: &'a str,
+    pub args: Vec<Arg<'a>>,
+}
+
+/// A literal argument to a filter. Strings are written in double quotes and cannot contain one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg<'a> {
+    Str(&'a str),
+    Int(i64),
+}
+
+impl<'a> Arg<'a> {
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Arg::Str(text) => Some(text),
+            Arg::Int(_) => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Arg::Int(i) => Some(*i),
+            Arg::Str(_) => None,
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    // Copies the node with each piece of text in it, in order, replaced by what `text` gives for it
+    pub(crate) fn map_text<'b>(&self, text: &mut impl FnMut(&'a str) -> &'b str) -> Node<'b> {
+        let nodes = |nodes: &[Node<'a>], text: &mut _| {
+            nodes.iter().map(|node| node.map_text(text)).collect()
+        };
+        match self {
+            Node::Literal(literal) => Node::Literal(text(literal)),
+            Node::Placeholder { name, filters } => Node::Placeholder {
+                name: text(name),
+                filters: filters
+                    .iter()
+                    .map(|filter| Filter {
+                        name: text(filter.name),
+                        args: filter
+                            .args
+                            .iter()
+                            .map(|arg| match *arg {
+                                Arg::Str(arg) => Arg::Str(text(arg)),
+                                Arg::Int(i) => Arg::Int(i),
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+            },
+            Node::If {
+                condition,
+                negated,
+                then,
+                otherwise,
+            } => Node::If {
+                condition: text(condition),
+                negated: *negated,
+                then: nodes(then, text),
+                otherwise: nodes(otherwise, text),
+            },
+            Node::Each {
+                list,
+                item,
+                body,
+                empty,
+            } => Node::Each {
+                list: text(list),
+                item: text(item),
+                body: nodes(body, text),
+                empty: nodes(empty, text),
+            },
+            Node::Extends(base) => Node::Extends(text(base)),
+            Node::Block { name, body } => Node::Block {
+                name: text(name),
+                body: nodes(body, text),
+            },
+            Node::Super => Node::Super,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    /// Plain text between two tags. May be empty
+    Text,
+    /// A doubled delimiter, like `{{` or `}}`. The text of the token is the single delimiter it
+    /// stands for
+    Escape,
+    /// The trimmed text between an open and a close delimiter
+    Placeholder,
+    /// The text between `{raw}` and `{/raw}`, which is copied through without looking for tags
+    Raw,
+    /// The text of a `{# comment #}`, which is left out of the output
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub(crate) kind: TokenKind,
+    pub(crate) text: &'a str,
+    /// Byte offset of the start of the token, including any braces, in the body
+    pub(crate) offset: usize,
+}
+
+/// Splits `body` into tokens in a single left to right pass, stopping at the first error.
+///
+/// The stream always alternates between a `Text` token and one other token, starting and ending
+/// with `Text`, so two tags next to each other have an empty `Text` token between them.
+pub(crate) fn tokenize<'a>(
+    body: &'a str,
+    syntax: &Syntax,
+    whitespace: Whitespace,
+) -> Result<Vec<Token<'a>>, ParseError> {
+    let (tokens, mut errors) = scan(body, syntax, whitespace);
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors.swap_remove(0))
+    }
+}
+
+/// How the whitespace around block tags like `{#if}` and `{/each}` is handled, on top of the
+/// `{- -}` markers any tag can have.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Whitespace {
+    /// Drop the first newline after a block tag
+    pub(crate) trim_blocks: bool,
+    /// Drop the spaces and tabs between the start of a line and a block tag
+    pub(crate) lstrip_blocks: bool,
+}
+
+// How much of the text after a tag is dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TrimAfter {
+    Nothing,
+    Newline,
+    Whitespace,
+}
+
+/// Like [`tokenize`], but keeps going after an error so every problem in the body is reported.
+/// A delimiter that caused an error is kept as part of the surrounding text.
+pub(crate) fn scan<'a>(
+    body: &'a str,
+    syntax: &Syntax,
+    whitespace: Whitespace,
+) -> (Vec<Token<'a>>, Vec<ParseError>) {
+    let (open, close) = (syntax.open(), syntax.close());
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+    // What the previous tag asked to drop from the start of the text after it
+    let mut trim_after = TrimAfter::Nothing;
+    let (mut opens, mut closes) = (Next::new(open), Next::new(close));
+
+    loop {
+        // Close delimiters only need looking at outside of a tag when they have to be doubled
+        let next_open = opens.from(body, i).map(|found| (found, open));
+        let next_close = match syntax.is_strict() {
+            true => closes.from(body, i).map(|found| (found, close)),
+            false => None,
+        };
+        let Some((brace, delimiter)) = next_open.into_iter().chain(next_close).min() else {
+            break;
+        };
+        let after = brace + delimiter.len();
+        let mut trim_before = false;
+        let mut trim_next = TrimAfter::Nothing;
+
+        let (mut kind, mut text, mut end) = if body[after..].starts_with(delimiter) {
+            // Keep the first delimiter of the pair as the literal
+            (
+                TokenKind::Escape,
+                &body[brace..after],
+                after + delimiter.len(),
+            )
+        } else if delimiter != open {
+            errors.push(ParseError::MissingOpenBrace(Location::new(body, brace)));
+            i = after;
+            continue;
+        } else if is_comment(&body[after..], close) {
+            // A comment may contain anything but its own end, delimiters included
+            let start = after + 1;
+            match body[start..]
+                .match_indices('#')
+                .find(|(found, _)| body[start + found + 1..].starts_with(close))
+            {
+                Some((found, _)) => (
+                    TokenKind::Comment,
+                    &body[start..start + found],
+                    start + found + 1 + close.len(),
+                ),
+                None => {
+                    errors.push(ParseError::UnclosedBlock(Location::new(body, brace)));
+                    i = after;
+                    continue;
+                }
+            }
+        } else {
+            // A tag may not contain another open delimiter before it is closed
+            let tag_close = closes.from(body, after);
+            let tag_open = opens.from(body, after);
+            match (tag_close, tag_open) {
+                (Some(tag_close), tag_open) if tag_open.is_none_or(|o| tag_close <= o) => {
+                    // `{-` and `-}` drop the whitespace on their side of the tag
+                    let mut tag = &body[after..tag_close];
+                    if let Some(rest) = tag.strip_prefix('-') {
+                        tag = rest;
+                        trim_before = true;
+                    }
+                    if let Some(rest) = tag.strip_suffix('-') {
+                        tag = rest;
+                        trim_next = TrimAfter::Whitespace;
+                    }
+                    (TokenKind::Placeholder, tag.trim(), tag_close + close.len())
+                }
+                _ => {
+                    errors.push(ParseError::MissingCloseBrace(Location::new(body, brace)));
+                    i = after;
+                    continue;
+                }
+            }
+        };
+
+        if kind == TokenKind::Placeholder && text == "raw" {
+            match raw_end(body, end, syntax) {
+                Some((raw_close, raw_end)) => {
+                    kind = TokenKind::Raw;
+                    text = &body[end..raw_close];
+                    end = raw_end;
+                    // The region itself is never trimmed
+                    trim_next = TrimAfter::Nothing;
+                }
+                None => {
+                    errors.push(ParseError::UnclosedBlock(Location::new(body, brace)));
+                    i = end;
+                    continue;
+                }
+            }
+        }
+
+        let block =
+            kind == TokenKind::Comment || (kind == TokenKind::Placeholder && is_block_tag(text));
+        if block && whitespace.trim_blocks {
+            trim_next = trim_next.max(TrimAfter::Newline);
+        }
+
+        let mut before = text_token(body, text_start, brace, trim_after);
+        if trim_before {
+            before.text = before.text.trim_end();
+        } else if block && whitespace.lstrip_blocks {
+            // Only when the tag is the first thing on its line
+            let stripped = before.text.trim_end_matches([' ', '\t']);
+            if stripped.ends_with('\n') || (stripped.is_empty() && before.offset == 0) {
+                before.text = stripped;
+            }
+        }
+        tokens.push(before);
+        tokens.push(Token {
+            kind,
+            text,
+            offset: brace,
+        });
+        i = end;
+        text_start = i;
+        trim_after = trim_next;
+    }
+
+    tokens.push(text_token(body, text_start, body.len(), trim_after));
+
+    (tokens, errors)
+}
+
+// Finds a delimiter in the body from some offset on. The match is kept, so asking again from an
+// offset up to it does not search the same text again and scanning the body stays linear
+struct Next<'p> {
+    pattern: &'p str,
+    // Where the last search started and what it found
+    last: Option<(usize, Option<usize>)>,
+}
+
+impl<'p> Next<'p> {
+    fn new(pattern: &'p str) -> Next<'p> {
+        Next {
+            pattern,
+            last: None,
+        }
+    }
+
+    fn from(&mut self, body: &str, i: usize) -> Option<usize> {
+        if let Some((start, found)) = self.last {
+            if start <= i && found.is_none_or(|found| i <= found) {
+                return found;
+            }
+        }
+        let found = body[i..].find(self.pattern).map(|found| i + found);
+        self.last = Some((i, found));
+        #[cfg(test)]
+        SEARCHED.with(|searched| {
+            let end = found.map_or(body.len(), |found| found + self.pattern.len());
+            searched.set(searched.get() + end - i);
+        });
+        found
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    // How many bytes of template bodies `Next` has searched on this thread
+    static SEARCHED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// The text between two tags, less what the tag before it asked to drop
+fn text_token(body: &str, start: usize, end: usize, trim: TrimAfter) -> Token<'_> {
+    let text = &body[start..end];
+    let text = match trim {
+        TrimAfter::Nothing => text,
+        TrimAfter::Newline => text
+            .strip_prefix('\n')
+            .or_else(|| text.strip_prefix("\r\n"))
+            .unwrap_or(text),
+        TrimAfter::Whitespace => text.trim_start(),
+    };
+    Token {
+        kind: TokenKind::Text,
+        text,
+        offset: end - text.len(),
+    }
+}
+
+// Whether the text of a tag makes it a block tag rather than a placeholder
+fn is_block_tag(text: &str) -> bool {
+    text.starts_with(['#', ':', '/', '%']) || text.starts_with("block ")
+}
+
+// Whether a tag starting with `tag` is a comment. `{#` followed by a space starts one, while
+// `{#if}` and the other blocks have a keyword right after the `#`
+fn is_comment(tag: &str, close: &str) -> bool {
+    match tag.strip_prefix('#') {
+        Some(rest) => {
+            rest.starts_with(char::is_whitespace)
+                || rest
+                    .strip_prefix('#')
+                    .is_some_and(|rest| rest.starts_with(close))
+        }
+        None => false,
+    }
+}
+
+// The start and end of the `{/raw}` tag that closes a raw region starting at `start`
+fn raw_end(body: &str, start: usize, syntax: &Syntax) -> Option<(usize, usize)> {
+    let mut i = start;
+    while let Some(found) = body[i..].find(syntax.open()) {
+        let tag = i + found;
+        i = tag + syntax.open().len();
+        let rest = body[i..].trim_start();
+        if let Some(rest) = rest.strip_prefix("/raw") {
+            if let Some(after) = rest.trim_start().strip_prefix(syntax.close()) {
+                return Some((tag, body.len() - after.len()));
+            }
+        }
+    }
+    None
+}
+
+pub(crate) fn parse_nodes<'a>(
+    body: &'a str,
+    syntax: &Syntax,
+    whitespace: Whitespace,
+) -> Result<Vec<Node<'a>>, ParseError> {
+    let mut errors = Vec::new();
+    let nodes = to_nodes(body, tokenize(body, syntax, whitespace)?, &mut errors);
+    match errors.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(nodes),
+    }
+}
+
+// A block tag that has been opened but not closed yet
+struct Block<'a> {
+    keyword: &'a str,
+    args: &'a str,
+    offset: usize,
+    nodes: Vec<Node<'a>>,
+    /// The nodes after `{:else}` or `{:empty}`, once it has been seen
+    alternative: Option<Vec<Node<'a>>>,
+}
+
+// Whether `args` are valid for a block opened with `keyword`
+fn valid_block(keyword: &str, args: &str) -> bool {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    match keyword {
+        "if" => !words.is_empty(),
+        "each" => matches!(words[..], [_, "as", _]),
+        "block" => words.len() == 1,
+        _ => false,
+    }
+}
+
+// The tag that starts the alternative nodes of a block, if it has any
+fn alternative_tag(keyword: &str) -> Option<&'static str> {
+    match keyword {
+        "if" => Some(":else"),
+        "each" => Some(":empty"),
+        _ => None,
+    }
+}
+
+impl<'a> Block<'a> {
+    fn push(&mut self, node: Node<'a>) {
+        self.alternative
+            .as_mut()
+            .unwrap_or(&mut self.nodes)
+            .push(node);
+    }
+
+    fn into_node(self) -> Node<'a> {
+        let alternative = self.alternative.unwrap_or_default();
+        if self.keyword == "block" {
+            return Node::Block {
+                name: self.args,
+                body: self.nodes,
+            };
+        } else if self.keyword == "each" {
+            let mut args = self.args.split_whitespace();
+            let list = args.next().unwrap_or_default();
+            return Node::Each {
+                list,
+                item: args.nth(1).unwrap_or_default(),
+                body: self.nodes,
+                empty: alternative,
+            };
+        }
+
+        let (negated, condition) = match self.args.strip_prefix('!') {
+            Some(condition) => (true, condition.trim()),
+            None => (false, self.args),
+        };
+
+        Node::If {
+            condition,
+            negated,
+            then: self.nodes,
+            otherwise: alternative,
+        }
+    }
+}
+
+/// Builds the node tree out of a token stream, matching up block tags like `{#if}` and `{/if}`.
+/// Problems are added to `errors` and skipped over, so a tree is returned either way.
+pub(crate) fn to_nodes<'a>(
+    body: &'a str,
+    tokens: Vec<Token<'a>>,
+    errors: &mut Vec<ParseError>,
+) -> Vec<Node<'a>> {
+    let mut root = Vec::new();
+    let mut open: Vec<Block<'a>> = Vec::new();
+
+    for token in tokens {
+        let node = match token.kind {
+            TokenKind::Text | TokenKind::Raw if token.text.is_empty() => continue,
+            TokenKind::Comment => continue,
+            TokenKind::Text | TokenKind::Escape | TokenKind::Raw => Node::Literal(token.text),
+            TokenKind::Placeholder => {
+                let text = token.text;
+                let unexpected = || ParseError::UnexpectedTag(Location::new(body, token.offset));
+
+                // `{#if a}` and `{#each a as b}` open blocks, as does `{block name}`
+                let open_tag = match text.strip_prefix('#') {
+                    Some(tag) => Some(tag),
+                    None => text.starts_with("block ").then_some(text),
+                };
+
+                if let Some(tag) = open_tag {
+                    let (keyword, args) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+                    if !valid_block(keyword, args.trim()) {
+                        errors.push(unexpected());
+                        continue;
+                    }
+                    open.push(Block {
+                        keyword,
+                        args: args.trim(),
+                        offset: token.offset,
+                        nodes: Vec::new(),
+                        alternative: None,
+                    });
+                    continue;
+                } else if text.starts_with(':') {
+                    match open.last_mut() {
+                        Some(block)
+                            if block.alternative.is_none()
+                                && alternative_tag(block.keyword) == Some(text) =>
+                        {
+                            block.alternative = Some(Vec::new())
+                        }
+                        _ => errors.push(unexpected()),
+                    }
+                    continue;
+                } else if let Some(keyword) = text.strip_prefix('/') {
+                    match open.last() {
+                        Some(block) if block.keyword == keyword.trim() => {
+                            open.pop().unwrap().into_node()
+                        }
+                        _ => {
+                            errors.push(unexpected());
+                            continue;
+                        }
+                    }
+                } else if let Some(statement) = text
+                    .strip_prefix('%')
+                    .and_then(|statement| statement.strip_suffix('%'))
+                {
+                    match statement.trim().strip_prefix("extends") {
+                        Some(name) if !name.trim().is_empty() => {
+                            Node::Extends(name.trim().trim_matches('"'))
+                        }
+                        _ => {
+                            errors.push(unexpected());
+                            continue;
+                        }
+                    }
+                } else if text == "super" && open.iter().any(|block| block.keyword == "block") {
+                    Node::Super
+                } else {
+                    match placeholder(text) {
+                        Some(node) => node,
+                        None => {
+                            errors.push(unexpected());
+                            continue;
+                        }
+                    }
+                }
+            }
+        };
+
+        match open.last_mut() {
+            Some(block) => block.push(node),
+            None => root.push(node),
+        }
+    }
+
+    // Close anything left open so the rest of the tree is still usable
+    while let Some(block) = open.pop() {
+        errors.push(ParseError::UnclosedBlock(Location::new(body, block.offset)));
+        let node = block.into_node();
+        match open.last_mut() {
+            Some(parent) => parent.push(node),
+            None => root.push(node),
+        }
+    }
+
+    root
+}
+
+// Reads `name`, `name?` or `name | filter | filter(args)`
+fn placeholder(text: &str) -> Option<Node<'_>> {
+    let mut parts = split_unquoted(text, '|').into_iter();
+    let mut name = parts.next().unwrap_or_default().trim();
+    let mut filters = Vec::new();
+    if let Some(optional) = name.strip_suffix('?') {
+        name = optional.trim();
+        filters.push(Filter {
+            name: "default",
+            args: vec![Arg::Str("")],
+        });
+    }
+
+    for part in parts {
+        filters.push(filter(part.trim())?);
+    }
+
+    // Names without a pipe are looked up exactly as written, and empty ones reported elsewhere
+    if filters.is_empty() {
+        name = text;
+    } else if name.is_empty() {
+        return None;
+    }
+    Some(Node::Placeholder { name, filters })
+}
+
+// Reads `name`, `name(args)` or `name: args`
+fn filter(text: &str) -> Option<Filter<'_>> {
+    let end = text
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let (name, rest) = text.split_at(end);
+    let rest = rest.trim_start();
+    let args = if let Some(args) = rest.strip_prefix('(') {
+        args.strip_suffix(')')?
+    } else if let Some(args) = rest.strip_prefix(':') {
+        args
+    } else if rest.is_empty() {
+        ""
+    } else {
+        return None;
+    };
+
+    let args = match args.trim() {
+        "" => Vec::new(),
+        args => split_unquoted(args, ',')
+            .into_iter()
+            .map(|arg| arg_literal(arg.trim()))
+            .collect::<Option<Vec<_>>>()?,
+    };
+
+    // `default` is applied by the renderer itself, so it is checked here
+    let valid = !name.is_empty() && (name != "default" || matches!(args[..], [Arg::Str(_)]));
+    valid.then_some(Filter { name, args })
+}
+
+fn arg_literal(text: &str) -> Option<Arg<'_>> {
+    match text.strip_prefix('"') {
+        Some(quoted) => {
+            let inner = quoted.strip_suffix('"')?;
+            (!inner.contains('"')).then_some(Arg::Str(inner))
+        }
+        None => text.parse().ok().map(Arg::Int),
+    }
+}
+
+// Splits `text` on `separator` wherever it is not inside double quotes
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// The byte offset of `part` in `body`. Every slice in a node or token borrows from the body it
+/// was parsed from, so this recovers where a node came from without storing it.
+pub(crate) fn offset_in(body: &str, part: &str) -> usize {
+    part.as_ptr() as usize - body.as_ptr() as usize
+}
+
+#[cfg(test)]
+mod render_helper_tests {
+    use super::*;
+
+    fn parse(body: &str) -> Result<Vec<Node<'_>>, ParseError> {
+        parse_nodes(body, &Syntax::default(), Whitespace::default())
+    }
+
+    // The (is_template, value) view of the token stream that the old recursive parser returned
+    fn render_helper(body: &str) -> Result<Vec<(bool, String)>, ParseError> {
+        Ok(tokenize(body, &Syntax::default(), Whitespace::default())?
+            .into_iter()
+            .map(|token| (token.kind == TokenKind::Placeholder, token.text.to_string()))
+            .collect())
+    }
+
+    #[test]
+    fn test_render_helper_missing_open() {
+        match render_helper("something }") {
+            Ok(_) => panic!("render_helper did not catch missing \"{{\""),
+            Err(ParseError::MissingOpenBrace(_)) => (),
+            _ => panic!("render_helper caught wrong error"),
+        }
+    }
+
+    #[test]
+    fn test_render_helper_missing_close() {
+        match render_helper("something {") {
+            Ok(_) => panic!("render_helper did not catch missing \"}}\""),
+            Err(ParseError::MissingCloseBrace(_)) => (),
+            _ => panic!("render_helper caught wrong error"),
+        }
+    }
+
+    #[test]
+    fn test_render_helper_success() {
+        let val = render_helper("This is a {successful} test of the {helper_function}").unwrap();
+        assert_eq!(
+            val,
+            vec![
+                (false, "This is a ".to_string()),
+                (true, "successful".to_string()),
+                (false, " test of the ".to_string()),
+                (true, "helper_function".to_string()),
+                (false, String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template_inside_template() {
+        if render_helper("{ {something} }").is_ok() {
+            panic!("render_helper allowed template inside another template");
+        }
+    }
+
+    #[test]
+    fn test_empty_template_with_helper() {
+        assert_eq!(
+            render_helper("{}").unwrap(),
+            vec![
+                (false, String::new()),
+                (true, String::new()),
+                (false, String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape_with_for_helper() {
+        assert_eq!(
+            render_helper("this should {{ be escaped }}").unwrap(),
+            vec![
+                (false, "this should ".to_string()),
+                (false, "{".to_string()),
+                (false, " be escaped ".to_string()),
+                (false, "}".to_string()),
+                (false, String::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_helper() {
+        let template_str = "{ template }{other_template} not template {{}}";
+        assert_eq!(
+            render_helper(template_str).unwrap(),
+            vec![
+                (false, String::new()),
+                (true, "template".to_string()),
+                (false, String::new()),
+                (true, "other_template".to_string()),
+                (false, " not template ".to_string()),
+                (false, "{".to_string()),
+                (false, String::new()),
+                (false, "}".to_string()),
+                (false, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_many_escapes_do_not_recurse() {
+        let body = "{{ \"a\": 1 }}".repeat(100_000);
+        let nodes = parse(&body).unwrap();
+        assert_eq!(nodes.len(), 300_000);
+        assert_eq!(
+            nodes[..3],
+            [
+                Node::Literal("{"),
+                Node::Literal(" \"a\": 1 "),
+                Node::Literal("}")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_one_sided_escapes_stay_linear() {
+        // Every delimiter of the other kind would otherwise be searched for to the end of the
+        // body at each step
+        fn searched(parse: impl FnOnce()) -> usize {
+            SEARCHED.with(|searched| searched.set(0));
+            parse();
+            SEARCHED.with(|searched| searched.get())
+        }
+
+        for escape in ["{{", "}}"] {
+            let body = escape.repeat(20_000);
+            let searched = searched(|| assert_eq!(parse(&body).unwrap().len(), 20_000));
+            assert!(searched <= 4 * body.len(), "searched {} bytes", searched);
+        }
+
+        let syntax = Syntax::new("<%", "%>");
+        let body = "<%<%".repeat(20_000);
+        let searched = searched(|| {
+            let tokens = tokenize(&body, &syntax, Whitespace::default()).unwrap();
+            assert_eq!(tokens.len(), 40_001);
+        });
+        assert!(searched <= 4 * body.len(), "searched {} bytes", searched);
+    }
+
+    #[test]
+    fn test_escapes_around_template() {
+        assert_eq!(
+            parse("{{{name}}}").unwrap(),
+            vec![
+                Node::Literal("{"),
+                Node::Placeholder {
+                    name: "name",
+                    filters: vec![],
+                },
+                Node::Literal("}")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_offsets() {
+        match tokenize("{a} }", &Syntax::default(), Whitespace::default()) {
+            Err(ParseError::MissingOpenBrace(loc)) => assert_eq!(loc.offset, 4),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match tokenize(
+            "{{ escaped }}\n{b {c}",
+            &Syntax::default(),
+            Whitespace::default(),
+        ) {
+            Err(ParseError::MissingCloseBrace(loc)) => {
+                assert_eq!((loc.offset, loc.line, loc.column), (14, 2, 1))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scan_reports_every_error() {
+        let (tokens, errors) = scan(
+            "} {ok} {open {{ }",
+            &Syntax::default(),
+            Whitespace::default(),
+        );
+        let offsets: Vec<usize> = errors
+            .iter()
+            .map(|err| err.location().unwrap().offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 7, 16]);
+        assert!(tokens
+            .iter()
+            .any(|token| token.kind == TokenKind::Placeholder && token.text == "ok"));
+    }
+
+    #[test]
+    fn test_if_blocks() {
+        assert_eq!(
+            parse("a{#if user}b{#if !admin}c{/if}{:else}d{/if}").unwrap(),
+            vec![
+                Node::Literal("a"),
+                Node::If {
+                    condition: "user",
+                    negated: false,
+                    then: vec![
+                        Node::Literal("b"),
+                        Node::If {
+                            condition: "admin",
+                            negated: true,
+                            then: vec![Node::Literal("c")],
+                            otherwise: vec![],
+                        },
+                    ],
+                    otherwise: vec![Node::Literal("d")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_errors() {
+        let cases = [
+            ("{#if a}", "UnclosedBlock", 0),
+            ("x{/if}", "UnexpectedTag", 1),
+            ("{:else}", "UnexpectedTag", 0),
+            ("{#if a}{:else}{:else}{/if}", "UnexpectedTag", 14),
+            ("{#if}{/if}", "UnexpectedTag", 0),
+            ("{#if a}{/each}", "UnexpectedTag", 7),
+            ("{#loop a}", "UnexpectedTag", 0),
+            ("{#each a}{/each}", "UnexpectedTag", 0),
+            ("{#each a as b}{:else}{/each}", "UnexpectedTag", 14),
+            ("{#if a}{:empty}{/if}", "UnexpectedTag", 7),
+            ("{block}", "Placeholder", 0),
+            ("{block a b}{/block}", "UnexpectedTag", 0),
+            ("{block a}{:else}{/block}", "UnexpectedTag", 9),
+            ("{% include \"a\" %}", "UnexpectedTag", 0),
+            ("{%extends%}", "UnexpectedTag", 0),
+        ];
+
+        for (body, kind, offset) in cases {
+            if kind == "Placeholder" {
+                assert!(parse(body).is_ok(), "{}", body);
+                continue;
+            }
+            let err = parse(body).unwrap_err();
+            assert!(
+                format!("{:?}", err).starts_with(kind),
+                "{}: {:?}",
+                body,
+                err
+            );
+            assert_eq!(err.location().unwrap().offset, offset, "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_each_blocks() {
+        assert_eq!(
+            parse("{#each rows as row}<{row}>{:empty}none{/each}").unwrap(),
+            vec![Node::Each {
+                list: "rows",
+                item: "row",
+                body: vec![
+                    Node::Literal("<"),
+                    Node::Placeholder {
+                        name: "row",
+                        filters: vec![],
+                    },
+                    Node::Literal(">")
+                ],
+                empty: vec![Node::Literal("none")],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_inheritance_tags() {
+        assert_eq!(
+            parse("{% extends \"base\" %}{block title}{super}!{/block}{super}").unwrap(),
+            vec![
+                Node::Extends("base"),
+                Node::Block {
+                    name: "title",
+                    body: vec![Node::Super, Node::Literal("!")],
+                },
+                Node::Placeholder {
+                    name: "super",
+                    filters: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_default_placeholders() {
+        let default = |text| Filter {
+            name: "default",
+            args: vec![Arg::Str(text)],
+        };
+        assert_eq!(
+            parse("{a?}{ b | default: \"none yet\" }").unwrap(),
+            vec![
+                Node::Placeholder {
+                    name: "a",
+                    filters: vec![default("")],
+                },
+                Node::Placeholder {
+                    name: "b",
+                    filters: vec![default("none yet")],
+                },
+            ]
+        );
+
+        for body in [
+            "{?}",
+            "{a | default}",
+            "{a | default: none}",
+            "{a | default(1)}",
+        ] {
+            let err = parse(body).unwrap_err();
+            assert!(matches!(err, ParseError::UnexpectedTag(_)), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            parse("{ title? | upper | truncate(40, \"..\") | date: \"%Y|%m\" | x() }").unwrap(),
+            vec![Node::Placeholder {
+                name: "title",
+                filters: vec![
+                    Filter {
+                        name: "default",
+                        args: vec![Arg::Str("")],
+                    },
+                    Filter {
+                        name: "upper",
+                        args: vec![],
+                    },
+                    Filter {
+                        name: "truncate",
+                        args: vec![Arg::Int(40), Arg::Str("..")],
+                    },
+                    Filter {
+                        name: "date",
+                        args: vec![Arg::Str("%Y|%m")],
+                    },
+                    Filter {
+                        name: "x",
+                        args: vec![],
+                    },
+                ],
+            }]
+        );
+
+        for body in [
+            "{a |}",
+            "{a | up per}",
+            "{a | f(1}",
+            "{a | f(x)}",
+            "{a | f(\"x)}",
+            "{| f}",
+        ] {
+            let err = parse(body).unwrap_err();
+            assert!(matches!(err, ParseError::UnexpectedTag(_)), "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_custom_delimiters() {
+        let syntax = Syntax::new("<%=", "%>");
+        let tokens = tokenize(
+            "a { <%= name %>} %> <%=<%= %>",
+            &syntax,
+            Whitespace::default(),
+        )
+        .unwrap();
+        let kinds: Vec<(TokenKind, &str)> = tokens
+            .iter()
+            .map(|token| (token.kind, token.text))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (TokenKind::Text, "a { "),
+                (TokenKind::Placeholder, "name"),
+                (TokenKind::Text, "} %> "),
+                (TokenKind::Escape, "<%="),
+                (TokenKind::Text, " %>"),
+            ]
+        );
+
+        let syntax = Syntax::new("${", "}");
+        assert_eq!(
+            parse_nodes("${#if a}{${a}}${/if}", &syntax, Whitespace::default()).unwrap(),
+            vec![Node::If {
+                condition: "a",
+                negated: false,
+                then: vec![
+                    Node::Literal("{"),
+                    Node::Placeholder {
+                        name: "a",
+                        filters: vec![],
+                    },
+                    Node::Literal("}"),
+                ],
+                otherwise: vec![],
+            }]
+        );
+
+        let (_, errors) = scan("${a ${b} ${c", &syntax, Whitespace::default());
+        let offsets: Vec<usize> = errors
+            .iter()
+            .map(|err| err.location().unwrap().offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 9]);
+    }
+
+    #[test]
+    fn test_raw_regions() {
+        assert_eq!(
+            parse("<script>{raw}var a = {b: {}};{/if}{ /raw }</script>{raw}{/raw}{c}").unwrap(),
+            vec![
+                Node::Literal("<script>"),
+                Node::Literal("var a = {b: {}};{/if}"),
+                Node::Literal("</script>"),
+                Node::Placeholder {
+                    name: "c",
+                    filters: vec![],
+                },
+            ]
+        );
+
+        // Errors after a raw region point at the right place
+        match parse("{raw}}{{/raw}\n}") {
+            Err(ParseError::MissingOpenBrace(loc)) => {
+                assert_eq!((loc.offset, loc.line, loc.column), (14, 2, 1))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let (_, errors) = scan("a {raw} {b", &Syntax::default(), Whitespace::default());
+        assert!(matches!(&errors[0], ParseError::UnclosedBlock(loc) if loc.offset == 2));
+        assert!(matches!(&errors[1], ParseError::MissingCloseBrace(loc) if loc.offset == 8));
+
+        let syntax = Syntax::new("[[", "]]");
+        assert_eq!(
+            parse_nodes("[[raw]][[x]][[/raw]]", &syntax, Whitespace::default()).unwrap(),
+            vec![Node::Literal("[[x]]")]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            parse("a{# note #}b{#\n  {missing} } {#if x}\n#}{##}c{#if x}{/if}").unwrap(),
+            vec![
+                Node::Literal("a"),
+                Node::Literal("b"),
+                Node::Literal("c"),
+                Node::If {
+                    condition: "x",
+                    negated: false,
+                    then: vec![],
+                    otherwise: vec![],
+                },
+            ]
+        );
+
+        let (tokens, errors) = scan("{# open {a}", &Syntax::default(), Whitespace::default());
+        assert!(matches!(&errors[..], [ParseError::UnclosedBlock(loc)] if loc.offset == 0));
+        assert!(tokens
+            .iter()
+            .any(|token| token.kind == TokenKind::Placeholder && token.text == "a"));
+
+        let syntax = Syntax::new("<%", "%>");
+        assert_eq!(
+            parse_nodes("<%# 50% #%> %>", &syntax, Whitespace::default()).unwrap(),
+            vec![Node::Literal(" %>")]
+        );
+    }
+
+    #[test]
+    fn test_trim_markers() {
+        assert_eq!(
+            parse("a \n {- b -} \n c {-#if x-}\n d\n{/if -}  {{ {-c}").unwrap(),
+            vec![
+                Node::Literal("a"),
+                Node::Placeholder {
+                    name: "b",
+                    filters: vec![],
+                },
+                Node::Literal("c"),
+                Node::If {
+                    condition: "x",
+                    negated: false,
+                    then: vec![Node::Literal("d\n")],
+                    otherwise: vec![],
+                },
+                Node::Literal("{"),
+                Node::Placeholder {
+                    name: "c",
+                    filters: vec![],
+                },
+            ]
+        );
+
+        // Offsets still point into the body after trimming
+        let tokens = tokenize("{a-}  \n b", &Syntax::default(), Whitespace::default()).unwrap();
+        assert_eq!((tokens[2].text, tokens[2].offset), ("b", 8));
+    }
+
+    #[test]
+    fn test_block_whitespace() {
+        let body = "<ul>\n  {#each xs as x}\n  <li>{x}</li>\n  {/each}\n</ul>  {#if x}\n{/if}";
+        let whitespace = Whitespace {
+            trim_blocks: true,
+            lstrip_blocks: true,
+        };
+        let tokens = tokenize(body, &Syntax::default(), whitespace).unwrap();
+        let texts: Vec<&str> = tokens
+            .iter()
+            .filter(|token| token.kind == TokenKind::Text)
+            .map(|token| token.text)
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["<ul>\n", "  <li>", "</li>\n", "</ul>  ", "", ""]
+        );
+
+        let trim_only = Whitespace {
+            trim_blocks: true,
+            lstrip_blocks: false,
+        };
+        let tokens = tokenize("{a}\n{# note #}\r\n\n", &Syntax::default(), trim_only).unwrap();
+        assert_eq!(tokens[2].text, "\n");
+        assert_eq!(tokens[4].text, "\n");
+    }
+}
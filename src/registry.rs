@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::compiled::{Binding, Parsed, State};
+use crate::{Context, NestedTemplate, ParseError};
+
+/// Named templates that any template rendered through the registry can insert by name, so a
+/// template used in many places is only held once. A placeholder is looked up in the registry
+/// last, after the context and the template's own values and sub-templates.
+///
+/// Templates are held behind an [`Arc`], so the same template can be shared between registries
+/// and cloning a registry is cheap. Each template is compiled when it is registered, so rendering
+/// it does not parse it again. One that fails to parse is parsed, and fails, whenever it is used.
+#[derive(Debug, Default, Clone)]
+pub struct Registry {
+    templates: HashMap<String, Arc<Entry>>,
+}
+
+// A registered template and the parse of it, if it parses
+#[derive(Debug)]
+struct Entry {
+    template: Arc<NestedTemplate>,
+    parsed: Option<Parsed>,
+}
+
+impl Entry {
+    fn new(template: Arc<NestedTemplate>) -> Entry {
+        let parsed = template.compile().ok().map(|compiled| compiled.to_parsed());
+        Entry { template, parsed }
+    }
+
+    fn binding(&self) -> Binding<'_> {
+        match &self.parsed {
+            Some(parsed) => Binding::Parsed(&self.template, parsed),
+            None => Binding::Template(&self.template),
+        }
+    }
+
+    fn render_in(&self, state: State) -> Result<String, ParseError> {
+        match &self.parsed {
+            Some(parsed) => {
+                let mut rendered_template = String::new();
+                parsed
+                    .compile(&self.template)
+                    .render_into(&mut rendered_template, state)?;
+                Ok(rendered_template)
+            }
+            None => self.template.render_in(state),
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Registers `template` under `name`, replacing any template already registered under it. A
+    /// template without a name of its own is named after the name it is registered under.
+    pub fn add_template(&mut self, name: &str, mut template: NestedTemplate) {
+        template.name.get_or_insert_with(|| name.to_string());
+        self.add_shared(name, Arc::new(template));
+    }
+
+    /// Registers a template that may be registered elsewhere as well.
+    pub fn add_shared(&mut self, name: &str, template: Arc<NestedTemplate>) {
+        let entry = Entry::new(template);
+        self.templates.insert(name.to_string(), Arc::new(entry));
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Arc<NestedTemplate>> {
+        let entry = self.templates.remove(name)?;
+        Some(Arc::clone(&entry.template))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<NestedTemplate>> {
+        self.templates.get(name).map(|entry| &entry.template)
+    }
+
+    // What a placeholder naming a registered template resolves to
+    pub(crate) fn binding(&self, name: &str) -> Option<Binding<'_>> {
+        self.templates.get(name).map(|entry| entry.binding())
+    }
+
+    /// The names of the registered templates, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    pub fn render(&self, name: &str) -> Result<String, ParseError> {
+        self.render_with(name, &Context::new())
+    }
+
+    /// Renders the template registered as `name`, looking placeholders up in `ctx` first like
+    /// [`NestedTemplate::render_with`] does.
+    pub fn render_with(&self, name: &str, ctx: &Context) -> Result<String, ParseError> {
+        let entry = self
+            .templates
+            .get(name)
+            .ok_or_else(|| ParseError::MissingTemplate(name.to_string()))?;
+        entry.render_in(State::with_registry(ctx, self))
+    }
+
+    /// Renders `template`, which does not have to be registered, with access to every registered
+    /// template.
+    pub fn render_template(
+        &self,
+        template: &NestedTemplate,
+        ctx: &Context,
+    ) -> Result<String, ParseError> {
+        template.render_in(State::with_registry(ctx, self))
+    }
+
+    pub fn validate(&self) -> Vec<ParseError> {
+        self.validate_with(&Context::new())
+    }
+
+    /// Validates every registered template, in name order. Placeholders that `ctx` provides are
+    /// not reported as missing.
+    pub fn validate_with(&self, ctx: &Context) -> Vec<ParseError> {
+        let mut names: Vec<&String> = self.templates.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .flat_map(|name| {
+                let template = &self.templates[name].template;
+                template.validate_in(State::with_registry(ctx, self))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod registry_tests {
+    use super::*;
+    use crate::Value;
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.add_template("header", NestedTemplate::new("<h1>{title}</h1>"));
+        registry.add_template("footer", NestedTemplate::new("<footer>{year}</footer>"));
+        registry
+    }
+
+    #[test]
+    fn test_templates_share_registered_templates() {
+        let mut registry = registry();
+        for page in ["home", "about"] {
+            let mut template = NestedTemplate::new("{header}<p>{body}</p>{footer}");
+            template.set_value("body", page);
+            template.set_value("title", "not visible to header");
+            registry.add_template(page, template);
+        }
+
+        let mut ctx = Context::new();
+        ctx.set_value("title", "Site");
+        ctx.set_value("year", 2024);
+        assert_eq!(
+            registry.render_with("about", &ctx).unwrap(),
+            "<h1>Site</h1><p>about</p><footer>2024</footer>"
+        );
+        assert!(registry.validate_with(&ctx).is_empty());
+
+        // Only the registered templates that use `title` and `year` miss them
+        let errors = registry.validate();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_local_templates_come_first() {
+        let registry = registry();
+        let mut page = NestedTemplate::new("{header}|{footer}");
+        page.add_sub_template("header", NestedTemplate::new("local"));
+        let mut ctx = Context::new();
+        ctx.set_value("year", 1999);
+        assert_eq!(
+            registry.render_template(&page, &ctx).unwrap(),
+            "local|<footer>1999</footer>"
+        );
+        // Without the registry only the local template is there
+        assert!(matches!(
+            page.render_with(&ctx),
+            Err(ParseError::MissingTemplate(name)) if name == "footer"
+        ));
+    }
+
+    #[test]
+    fn test_shared_between_registries() {
+        let header = Arc::new(NestedTemplate::new("{title}"));
+        let mut first = Registry::new();
+        first.add_shared("header", Arc::clone(&header));
+        let mut second = first.clone();
+        second.add_template("page", NestedTemplate::new("[{header}]"));
+        first.add_template("page", NestedTemplate::new("({header})"));
+
+        let mut ctx = Context::new();
+        ctx.set_value("title", "x");
+        assert_eq!(first.render_with("page", &ctx).unwrap(), "(x)");
+        assert_eq!(second.render_with("page", &ctx).unwrap(), "[x]");
+        // The clone shares the compiled entry rather than the template alone
+        assert_eq!(Arc::strong_count(&header), 2);
+        assert!(Arc::ptr_eq(first.get("header").unwrap(), &header));
+
+        second.remove("page");
+        assert!(second.render("page").is_err());
+    }
+
+    #[test]
+    fn test_broken_templates_fail_when_used() {
+        let mut registry = registry();
+        registry.add_template("broken", NestedTemplate::new("{title"));
+        registry.add_template("page", NestedTemplate::new("{header}{broken}"));
+        let mut ctx = Context::new();
+        ctx.set_value("title", "x");
+        assert_eq!(registry.render_with("header", &ctx).unwrap(), "<h1>x</h1>");
+        assert!(matches!(
+            registry.render_with("page", &ctx),
+            Err(ParseError::MissingCloseBrace(_))
+        ));
+
+        let page = registry.remove("page").unwrap();
+        drop(registry);
+        assert_eq!(page.body, "{header}{broken}");
+
+        fn shared<T: Send + Sync>() {}
+        shared::<Registry>();
+    }
+
+    #[test]
+    fn test_parsed_once() {
+        let mut page = NestedTemplate::new(concat!(
+            "{% extends \"layout.html\" %}{block main}{#each xs as x}",
+            "<a href=\"{x.url}\">{x.name | truncate(3)}</a>{:empty}{note?}{/each}{/block}",
+        ));
+        page.add_sub_template("note", NestedTemplate::new("<i title=\"{a}\">{a}</i>"));
+        let entry = Entry::new(Arc::new(page));
+
+        // Compiling the parse again gives the nodes that parsing the body does
+        let parsed = entry.parsed.as_ref().unwrap();
+        let compiled = entry.template.compile().unwrap();
+        assert_eq!(parsed.compile(&entry.template).nodes(), compiled.nodes());
+
+        let mut registry = Registry::new();
+        registry.add_template(
+            "layout.html",
+            NestedTemplate::new("<main>{block main}{/block}</main>"),
+        );
+        registry
+            .templates
+            .insert("page".to_string(), Arc::new(entry));
+        let mut ctx = Context::new();
+        ctx.set_value("a", "\"&");
+        ctx.set_value("xs", Value::List(Vec::new()));
+        let xs: Value = [("url", "/x?a=b"), ("name", "Tom & Jerry")]
+            .into_iter()
+            .collect();
+        for _ in 0..2 {
+            assert_eq!(
+                registry.render_with("page", &ctx).unwrap(),
+                "<main><i title=\"&quot;&amp;\">&quot;&amp;</i></main>"
+            );
+        }
+        ctx.set_value("xs", Value::from(vec![xs]));
+        assert_eq!(
+            registry.render_with("page", &ctx).unwrap(),
+            "<main><a href=\"/x?a=b\">Tom...</a></main>"
+        );
+    }
+}
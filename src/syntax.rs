@@ -0,0 +1,47 @@
+/// The delimiters that open and close a tag in a template body, `{` and `}` by default.
+///
+/// Writing the open delimiter twice in a row inserts it literally, like `{{` does by default. With
+/// the default braces a lone `}` is an error and `}}` inserts a single one. With any other
+/// delimiters a close delimiter outside of a tag is just text, so the braces in CSS, JavaScript or
+/// JSON don't need doubling once the template uses something like `<%= %>` or `[[ ]]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Syntax {
+    open: String,
+    close: String,
+}
+
+impl Syntax {
+    /// # Panics
+    ///
+    /// If either delimiter is empty.
+    pub fn new(open: &str, close: &str) -> Syntax {
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "template delimiters cannot be empty"
+        );
+        Syntax {
+            open: open.to_string(),
+            close: close.to_string(),
+        }
+    }
+
+    pub fn open(&self) -> &str {
+        &self.open
+    }
+
+    pub fn close(&self) -> &str {
+        &self.close
+    }
+
+    // Whether a close delimiter outside of a tag has to be doubled, which is only the case for
+    // the original braces
+    pub(crate) fn is_strict(&self) -> bool {
+        self.open == "{" && self.close == "}"
+    }
+}
+
+impl Default for Syntax {
+    fn default() -> Syntax {
+        Syntax::new("{", "}")
+    }
+}
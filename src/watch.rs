@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::loader::list_files;
+
+/// What changed under a watched directory since the last time the watcher was asked.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    /// The files and directories that were written, created or removed
+    pub(crate) paths: HashSet<PathBuf>,
+    /// Set when changes may have been missed, so every file has to be looked at again
+    pub(crate) everything: bool,
+}
+
+/// Watches a directory and the directories under it, leaving out hidden ones and links, for
+/// files that change. Uses inotify on Linux, which only costs a system call when nothing has
+/// changed. Elsewhere, or if inotify cannot be set up, it compares the modification time and
+/// length of every file each time it is asked instead.
+#[derive(Debug)]
+pub(crate) enum Watcher {
+    Inotify(inotify::Inotify),
+    Poll(Poll),
+}
+
+impl Watcher {
+    pub(crate) fn new(root: &Path) -> Watcher {
+        match inotify::Inotify::new(root) {
+            Ok(inotify) => Watcher::Inotify(inotify),
+            Err(_) => Watcher::Poll(Poll::new(root)),
+        }
+    }
+
+    pub(crate) fn changes(&mut self) -> Changes {
+        match self {
+            Watcher::Inotify(inotify) => inotify.changes(),
+            Watcher::Poll(poll) => poll.changes(),
+        }
+    }
+
+    /// Starts watching a directory that was created under the root since the watcher was.
+    pub(crate) fn add_dir(&mut self, dir: &Path) {
+        match self {
+            Watcher::Inotify(inotify) => {
+                // Changes in a directory that can't be watched can't be reloaded either, which
+                // is no worse than not having the directory in the first place
+                let _ = inotify.watch(dir);
+            }
+            Watcher::Poll(_) => (),
+        }
+    }
+}
+
+/// Finds changes by looking at every file.
+#[derive(Debug)]
+pub(crate) struct Poll {
+    root: PathBuf,
+    stamps: HashMap<PathBuf, Option<Stamp>>,
+}
+
+// When a file was last modified and its length, which together tell whether it has changed
+// since it was read even where modification times are coarse
+type Stamp = (Option<SystemTime>, u64);
+
+impl Poll {
+    pub(crate) fn new(root: &Path) -> Poll {
+        let mut poll = Poll {
+            root: root.to_path_buf(),
+            stamps: HashMap::new(),
+        };
+        poll.changes();
+        poll
+    }
+
+    fn changes(&mut self) -> Changes {
+        let Ok(paths) = list_files(&self.root) else {
+            return Changes {
+                everything: true,
+                ..Changes::default()
+            };
+        };
+
+        let mut changes = Changes::default();
+        let mut stamps = HashMap::with_capacity(paths.len());
+        for path in paths {
+            let stamp = fs::metadata(&path)
+                .ok()
+                .map(|metadata| (metadata.modified().ok(), metadata.len()));
+            if self.stamps.remove(&path) != Some(stamp) {
+                changes.paths.insert(path.clone());
+            }
+            stamps.insert(path, stamp);
+        }
+        // What is left is gone
+        changes
+            .paths
+            .extend(self.stamps.drain().map(|(path, _)| path));
+        self.stamps = stamps;
+        changes
+    }
+}
+
+// Every directory under `dir`, following the same rules as `list_files`
+fn list_dirs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            let path = entry.path();
+            dirs.extend(list_dirs(&path)?);
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+// The flags passed to inotify_init1 have these values on the common architectures only
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::{c_char, c_int, CString, OsStr};
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    use super::{list_dirs, Changes};
+
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+    }
+
+    const IN_NONBLOCK: c_int = 0o4000;
+    const IN_CLOEXEC: c_int = 0o2000000;
+
+    const IN_MODIFY: u32 = 0x2;
+    const IN_ATTRIB: u32 = 0x4;
+    const IN_CLOSE_WRITE: u32 = 0x8;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_IGNORED: u32 = 0x8000;
+    const IN_ONLYDIR: u32 = 0x0100_0000;
+    const IN_DONT_FOLLOW: u32 = 0x0200_0000;
+
+    const MASK: u32 = IN_MODIFY
+        | IN_ATTRIB
+        | IN_CLOSE_WRITE
+        | IN_MOVED_FROM
+        | IN_MOVED_TO
+        | IN_CREATE
+        | IN_DELETE
+        | IN_ONLYDIR
+        | IN_DONT_FOLLOW;
+
+    // The size of `struct inotify_event` before the name that follows it
+    const EVENT_SIZE: usize = 16;
+
+    #[derive(Debug)]
+    pub(crate) struct Inotify {
+        file: File,
+        // The directory each watch descriptor belongs to
+        dirs: HashMap<i32, PathBuf>,
+    }
+
+    impl Inotify {
+        pub(crate) fn new(root: &Path) -> io::Result<Inotify> {
+            // SAFETY: takes no pointers, and the descriptor it returns is checked
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the descriptor was just opened and nothing else owns it
+            let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            let mut inotify = Inotify {
+                file,
+                dirs: HashMap::new(),
+            };
+            inotify.watch(root)?;
+            Ok(inotify)
+        }
+
+        // Watches `dir` and every directory under it
+        pub(crate) fn watch(&mut self, dir: &Path) -> io::Result<()> {
+            self.add_watch(dir)?;
+            for dir in list_dirs(dir)? {
+                self.add_watch(&dir)?;
+            }
+            Ok(())
+        }
+
+        fn add_watch(&mut self, dir: &Path) -> io::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            // SAFETY: `path` is a NUL terminated string that lives until the call returns
+            let wd = unsafe { inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+            Ok(())
+        }
+
+        pub(crate) fn changes(&mut self) -> Changes {
+            let mut changes = Changes::default();
+            // Large enough for at least one event with the longest name a file can have
+            let mut buffer = [0; 4096];
+            loop {
+                match self.file.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(read) => self.read_events(&buffer[..read], &mut changes),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                    Err(_) => {
+                        changes.everything = true;
+                        break;
+                    }
+                }
+            }
+            changes
+        }
+
+        fn read_events(&mut self, mut events: &[u8], changes: &mut Changes) {
+            while events.len() >= EVENT_SIZE {
+                let field = |at: usize| {
+                    u32::from_ne_bytes([events[at], events[at + 1], events[at + 2], events[at + 3]])
+                };
+                let (wd, mask, len) = (field(0) as i32, field(4), field(12) as usize);
+                let end = (EVENT_SIZE + len).min(events.len());
+                // The name is padded with NULs
+                let name = events[EVENT_SIZE..end].split(|&byte| byte == 0).next();
+                events = &events[end..];
+
+                if mask & IN_Q_OVERFLOW != 0 {
+                    changes.everything = true;
+                } else if mask & IN_IGNORED != 0 {
+                    self.dirs.remove(&wd);
+                } else if let (Some(dir), Some(name)) = (self.dirs.get(&wd), name) {
+                    if !name.is_empty() {
+                        changes.paths.insert(dir.join(OsStr::from_bytes(name)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+mod inotify {
+    use std::io;
+    use std::path::Path;
+
+    use super::Changes;
+
+    #[derive(Debug)]
+    pub(crate) enum Inotify {}
+
+    impl Inotify {
+        pub(crate) fn new(_: &Path) -> io::Result<Inotify> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub(crate) fn watch(&mut self, _: &Path) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub(crate) fn changes(&mut self) -> Changes {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nested-template-watch-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/a.html"), "a").unwrap();
+        dir
+    }
+
+    fn check(dir: &Path, mut watcher: Watcher) {
+        assert!(watcher.changes().paths.is_empty());
+        fs::write(dir.join("sub/a.html"), "changed").unwrap();
+        fs::write(dir.join("b.html"), "b").unwrap();
+        let changes = watcher.changes();
+        assert!(changes.paths.contains(&dir.join("sub/a.html")));
+        assert!(changes.paths.contains(&dir.join("b.html")));
+
+        fs::remove_file(dir.join("b.html")).unwrap();
+        assert!(watcher.changes().paths.contains(&dir.join("b.html")));
+        assert!(watcher.changes().paths.is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_watcher() {
+        // Inotify may be denied or out of instances, in which case the watcher polls instead
+        let dir = temp_dir("watcher");
+        check(&dir, Watcher::new(&dir));
+    }
+
+    #[test]
+    fn test_inotify() {
+        let dir = temp_dir("inotify");
+        match inotify::Inotify::new(&dir) {
+            Ok(inotify) => check(&dir, Watcher::Inotify(inotify)),
+            Err(_) => {
+                let _ = fs::remove_dir_all(&dir);
+            }
+        }
+    }
+
+    #[test]
+    fn test_poll() {
+        let dir = temp_dir("poll");
+        check(&dir, Watcher::Poll(Poll::new(&dir)));
+    }
+}